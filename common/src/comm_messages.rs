@@ -3,6 +3,9 @@ use serde::{Deserialize, Serialize};
 
 pub const MAX_UPLINK_MSG_SIZE: usize = 256;
 
+// Baud rate of the serial link, unless both ends are configured otherwise
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
 // We use COBS to "encode" the messages. Each message is simply encoded by postcard in COBS
 // mode, and a zero is sent after each message.
 
@@ -10,4 +13,16 @@ pub const MAX_UPLINK_MSG_SIZE: usize = 256;
 pub enum UplinkMsg {
     PushPLLChange(PLLChange),
     PushFracn(u8, [u16; 32]),
+    // Only acknowledged, to check that the transmitter is alive
+    Ping(),
+    // The sequence being uploaded is complete. Only acknowledged, as the transmitter queues
+    // each command as it arrives.
+    UploadDone(),
+    // Starts a new upload. Until started, it discards the sequence uploaded but not started yet.
+    // Once playing, uploads are queued after the sequence being played instead.
+    ClearBuffer(),
+    // Starts playing the queued sequences, which play back to back until stopped
+    StartNow(),
+    // Stops playing and disables the output immediately, discarding queued commands
+    StopNow(),
 }
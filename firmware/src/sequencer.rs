@@ -1,6 +1,13 @@
-use core::hint::black_box;
+use core::{
+    cell::Cell,
+    hint::black_box,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use common::{comm_messages::UplinkMsg, sequence::PLLChange};
+use common::{
+    comm_messages::{DEFAULT_BAUD_RATE, UplinkMsg},
+    sequence::{MAX_DIVN_CHANGES, MAX_SEQUENCE_LEN, PLLChange},
+};
 use embassy_futures::join;
 use embassy_stm32::{
     Peri, bind_interrupts, pac, peripherals,
@@ -24,11 +31,22 @@ enum FreqCommand {
 // This signal is used to send commands to the PLL
 static LIVE_COMMAND: Signal<CriticalSectionRawMutex, FreqCommand> = Signal::new();
 // This acts as a buffer between incoming data and data being sent to the PLL, it can either suppose a fracn change
-// or a notification of an incoming PLL change
-static COMMAND_CHANNEL: Channel<CriticalSectionRawMutex, FreqCommand, 4096> = Channel::new();
+// or a notification of an incoming PLL change. It holds the sequence being played and the one being uploaded.
+static COMMAND_CHANNEL: Channel<
+    CriticalSectionRawMutex,
+    FreqCommand,
+    { 2 * (MAX_SEQUENCE_LEN + MAX_DIVN_CHANGES) },
+> = Channel::new();
 // This acts as a buffer for PLL changes, as they are relatively uncommon but heavyweight, to prevent
 // the commands from having to carry them
-static PLL_CHANGE_CHANNEL: Channel<CriticalSectionRawMutex, PLLChange, 8> = Channel::new();
+static PLL_CHANGE_CHANNEL: Channel<CriticalSectionRawMutex, PLLChange, { 2 * MAX_DIVN_CHANGES }> =
+    Channel::new();
+// Set by StartNow, until stopped. Queued commands are only played once started.
+static STARTED: AtomicBool = AtomicBool::new(false);
+static START_SIGNAL: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+// Number of times the commands have been cleared. A command taken by the sequencer is only played
+// if no clear happened since, and both are done under this lock so they can't interleave.
+static CLEAR_COUNT: CriticalSectionMutex<Cell<u32>> = CriticalSectionMutex::new(Cell::new(0));
 
 fn setup_pll2() {
     let rcc = pac::RCC;
@@ -110,8 +128,12 @@ pub async fn pll_controller_task() {
 
         match cmd {
             FreqCommand::Fracn(fracn) => handle_fracn(fracn),
+            // The PLL change is always queued before its command, so it's only missing if
+            // both were cleared while the command was being played
             FreqCommand::Change() => {
-                handle_pllchange(PLL_CHANGE_CHANNEL.receive().await);
+                if let Ok(change) = PLL_CHANGE_CHANNEL.try_receive() {
+                    handle_pllchange(change);
+                }
             }
         }
     }
@@ -123,15 +145,82 @@ pub async fn sequencer_task() {
 
     loop {
         // Wait for timer (or command if none are available just yet)
-        let (cmd, _) = join::join(COMMAND_CHANNEL.receive(), Timer::after_micros(sleep_us)).await;
-        LIVE_COMMAND.signal(cmd);
+        let take_command = async {
+            loop {
+                let taken = CLEAR_COUNT.lock(|count| {
+                    if !STARTED.load(Ordering::Relaxed) {
+                        return None;
+                    }
+                    let cmd = COMMAND_CHANNEL.try_receive().ok()?;
+                    Some((cmd, count.get()))
+                });
+                if let Some(taken) = taken {
+                    return taken;
+                }
+                if STARTED.load(Ordering::Relaxed) {
+                    COMMAND_CHANNEL.ready_to_receive().await;
+                } else {
+                    START_SIGNAL.wait().await;
+                }
+            }
+        };
+        let ((cmd, taken_at), _) = join::join(take_command, Timer::after_micros(sleep_us)).await;
+
+        // Drop the command if the commands were cleared while it was held
+        CLEAR_COUNT.lock(|count| {
+            if count.get() == taken_at {
+                LIVE_COMMAND.signal(cmd);
+            }
+        });
+    }
+}
+
+// The baud rate may be changed when building, with the DIRECT_RF_BAUD environment variable.
+// It's parsed at compile time, so an invalid value fails the build.
+const BAUD_RATE: u32 = match option_env!("DIRECT_RF_BAUD") {
+    Some(baud) => parse_baud(baud),
+    None => DEFAULT_BAUD_RATE,
+};
+
+const fn parse_baud(baud: &str) -> u32 {
+    let bytes = baud.as_bytes();
+    if bytes.is_empty() {
+        panic!("DIRECT_RF_BAUD must be a number");
     }
+
+    let mut out: u64 = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if !bytes[i].is_ascii_digit() {
+            panic!("DIRECT_RF_BAUD must be a number");
+        }
+        out = out * 10 + (bytes[i] - b'0') as u64;
+        if out > u32::MAX as u64 {
+            panic!("DIRECT_RF_BAUD is too large");
+        }
+        i += 1;
+    }
+
+    if out == 0 {
+        panic!("DIRECT_RF_BAUD must be positive");
+    }
+    out as u32
 }
 
 bind_interrupts!(struct Irqs {
     USART3 => usart::InterruptHandler<peripherals::USART3>;
 });
 
+// Discards every queued command, including the one about to be played
+fn clear_commands() {
+    CLEAR_COUNT.lock(|count| {
+        COMMAND_CHANNEL.clear();
+        PLL_CHANGE_CHANNEL.clear();
+        LIVE_COMMAND.reset();
+        count.set(count.get().wrapping_add(1));
+    });
+}
+
 async fn handle_comm_msg(msg: UplinkMsg) {
     match msg {
         UplinkMsg::PushPLLChange(pllchange) => {
@@ -145,6 +234,25 @@ async fn handle_comm_msg(msg: UplinkMsg) {
                     .await;
             }
         }
+        UplinkMsg::StartNow() => {
+            STARTED.store(true, Ordering::Relaxed);
+            START_SIGNAL.signal(());
+        }
+        UplinkMsg::StopNow() => {
+            STARTED.store(false, Ordering::Relaxed);
+            clear_commands();
+            // Disable the output, it's enabled again by the next PLL change
+            pac::RCC.pllcfgr().modify(|w| w.set_divpen(2, false));
+        }
+        // Once started, uploads are queued after the sequence being played, which must not be
+        // discarded. Before that, the queue only holds sequences that haven't started.
+        UplinkMsg::ClearBuffer() => {
+            if !STARTED.load(Ordering::Relaxed) {
+                clear_commands();
+            }
+        }
+        // Commands are queued as they arrive, so there's nothing left to do once uploaded
+        UplinkMsg::Ping() | UplinkMsg::UploadDone() => {}
     }
 }
 
@@ -157,7 +265,7 @@ pub async fn comm_task(
     rx_dma: Peri<'static, peripherals::GPDMA1_CH1>,
 ) {
     let mut config = usart::Config::default();
-    config.baudrate = 1_000_000;
+    config.baudrate = BAUD_RATE;
 
     let mut uart = Uart::new(
         uart,
@@ -166,7 +274,7 @@ pub async fn comm_task(
         Irqs,
         tx_dma,
         rx_dma,
-        usart::Config::default(),
+        config,
     )
     .unwrap();
 
@@ -0,0 +1,310 @@
+//! Band plans restrict where and for how long the transmitter may emit. They are CSV files
+//! where the first value of each row gives its kind:
+//!
+//! ```text
+//! # Fundamental frequencies must lie within one of the allowed bands (if any is given)
+//! allow,     7MHz,    7.2MHz
+//! # Neither the fundamental nor its odd harmonics may fall within a forbidden band
+//! forbid,    7.05MHz, 7.06MHz
+//! forbid,    21MHz,   21.45MHz
+//! # Channels of 10kHz may not be occupied continuously for longer than 400ms
+//! dwell,     10kHz,   400ms
+//! # Highest odd harmonic of the square wave output that is checked (7 by default)
+//! harmonics, 9
+//! ```
+//!
+//! Values accept the same unit suffixes as orders files.
+
+use std::fmt;
+
+use crate::orders::{self, DURATION_UNITS, FREQ_UNITS, Field};
+
+const DEFAULT_MAX_HARMONIC: usize = 7;
+
+/// Error while parsing a band plan, with 1-based line and column
+#[derive(Debug)]
+pub struct BandPlanParseError {
+    pub line: usize,
+    pub column: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for BandPlanParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.msg
+        )
+    }
+}
+
+impl std::error::Error for BandPlanParseError {}
+
+/// Limit on continuous occupation of a channel
+#[derive(Clone, Copy, Debug)]
+pub struct DwellLimit {
+    pub channel_hz: f64,
+    pub max_s: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct BandPlan {
+    /// (low, high) bands where the fundamental may be
+    pub allowed: Vec<(f64, f64)>,
+    /// (low, high) bands where neither the fundamental nor its harmonics may be
+    pub forbidden: Vec<(f64, f64)>,
+    pub dwell: Option<DwellLimit>,
+    /// Highest odd harmonic checked against the forbidden bands
+    pub max_harmonic: usize,
+}
+
+/// A hop of the frequency timeline that breaks the band plan
+#[derive(Debug)]
+pub struct Violation {
+    /// Epoch time at which the offending hop starts
+    pub time_s: f64,
+    pub freq_hz: f64,
+    pub msg: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "at {:.6}s, {:.0}Hz: {}",
+            self.time_s, self.freq_hz, self.msg
+        )
+    }
+}
+
+fn in_band(bands: &[(f64, f64)], freq: f64) -> Option<(f64, f64)> {
+    bands
+        .iter()
+        .copied()
+        .find(|&(low, high)| freq >= low && freq <= high)
+}
+
+impl BandPlan {
+    /// Checks a frequency timeline as returned by `build_frequencies`. Each hop lasts until
+    /// the next one starts, and the last one as long as the one before it.
+    pub fn check(&self, freqs: &[(f64, f64)]) -> Vec<Violation> {
+        let mut out = Vec::new();
+
+        for &(time_s, freq_hz) in freqs {
+            if !self.allowed.is_empty() && in_band(&self.allowed, freq_hz).is_none() {
+                out.push(Violation {
+                    time_s,
+                    freq_hz,
+                    msg: String::from("Outside of the allowed bands"),
+                });
+            }
+
+            for harmonic in (1..=self.max_harmonic).step_by(2) {
+                let emitted = freq_hz * harmonic as f64;
+                if let Some((low, high)) = in_band(&self.forbidden, emitted) {
+                    let what = if harmonic == 1 {
+                        String::from("Fundamental")
+                    } else {
+                        format!("Harmonic {} ({:.0}Hz)", harmonic, emitted)
+                    };
+                    out.push(Violation {
+                        time_s,
+                        freq_hz,
+                        msg: format!("{} within forbidden band {:.0}-{:.0}Hz", what, low, high),
+                    });
+                }
+            }
+        }
+
+        if let Some(dwell) = self.dwell {
+            out.extend(check_dwell(freqs, dwell));
+        }
+
+        out.sort_by(|a, b| a.time_s.total_cmp(&b.time_s));
+        out
+    }
+}
+
+fn check_dwell(freqs: &[(f64, f64)], dwell: DwellLimit) -> Vec<Violation> {
+    let mut out = Vec::new();
+    let channel = |freq: f64| (freq / dwell.channel_hz).floor() as i64;
+
+    // Start of the current run of hops in the same channel
+    let mut run_start = 0;
+    for i in 0..freqs.len() {
+        let end_s = match freqs.get(i + 1) {
+            Some(next) => next.0,
+            None if i > 0 => 2.0 * freqs[i].0 - freqs[i - 1].0,
+            None => freqs[i].0,
+        };
+        let continues = freqs
+            .get(i + 1)
+            .is_some_and(|next| channel(next.1) == channel(freqs[i].1));
+        if continues {
+            continue;
+        }
+
+        let (start_s, freq_hz) = freqs[run_start];
+        let held_s = end_s - start_s;
+        if held_s > dwell.max_s {
+            out.push(Violation {
+                time_s: start_s,
+                freq_hz,
+                msg: format!(
+                    "Channel held for {:.6}s, more than the maximum dwell of {:.6}s",
+                    held_s, dwell.max_s
+                ),
+            });
+        }
+        run_start = i + 1;
+    }
+
+    out
+}
+
+pub fn parse_bandplan(file: String) -> Result<BandPlan, BandPlanParseError> {
+    let number = orders::number_regex();
+    let mut out = BandPlan {
+        allowed: Vec::new(),
+        forbidden: Vec::new(),
+        dwell: None,
+        max_harmonic: DEFAULT_MAX_HARMONIC,
+    };
+
+    for (i, full_line) in file.lines().enumerate() {
+        let line = full_line.split('#').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = orders::split_fields(line);
+        let error = |column: usize, msg: String| BandPlanParseError {
+            line: i + 1,
+            column,
+            msg,
+        };
+        let expect = |n: usize| {
+            if let Some(extra) = fields.get(n) {
+                return Err(error(
+                    extra.column,
+                    format!("Expected at most {} values", n),
+                ));
+            }
+            match fields.get(n - 1) {
+                Some(f) if !f.text.is_empty() => Ok(()),
+                Some(f) => Err(error(f.column, format!("Expected {} values", n))),
+                None => Err(error(
+                    line.trim_end().len() + 1,
+                    format!("Expected {} values", n),
+                )),
+            }
+        };
+        let quantity = |field: &Field, units: &[(&str, f64)]| {
+            orders::parse_quantity(&number, field.text, units)
+                .map_err(|msg| error(field.column, msg))
+        };
+        let band = |fields: &[Field]| {
+            let low = quantity(&fields[1], &FREQ_UNITS)?;
+            let high = quantity(&fields[2], &FREQ_UNITS)?;
+            if high <= low {
+                return Err(error(
+                    fields[2].column,
+                    String::from("Band must end above its start"),
+                ));
+            }
+            Ok((low, high))
+        };
+
+        match fields[0].text {
+            "allow" => {
+                expect(3)?;
+                out.allowed.push(band(&fields)?);
+            }
+            "forbid" => {
+                expect(3)?;
+                out.forbidden.push(band(&fields)?);
+            }
+            "dwell" => {
+                expect(3)?;
+                let channel_hz = quantity(&fields[1], &FREQ_UNITS)?;
+                if channel_hz <= 0.0 {
+                    return Err(error(
+                        fields[1].column,
+                        String::from("Channel width must be positive"),
+                    ));
+                }
+                let max_s = quantity(&fields[2], &DURATION_UNITS)? * 1e-6;
+                out.dwell = Some(DwellLimit { channel_hz, max_s });
+            }
+            "harmonics" => {
+                expect(2)?;
+                out.max_harmonic = fields[1].text.parse().map_err(|_| {
+                    error(
+                        fields[1].column,
+                        format!("Invalid integer '{}'", fields[1].text),
+                    )
+                })?;
+                if out.max_harmonic < 1 {
+                    return Err(error(
+                        fields[1].column,
+                        String::from("Highest harmonic must be at least 1"),
+                    ));
+                }
+            }
+            other => {
+                return Err(error(
+                    fields[0].column,
+                    format!("Unknown band plan entry '{}'", other),
+                ));
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_out_of_band_harmonic_and_dwell_violations() {
+        let plan = parse_bandplan(
+            "allow, 7MHz, 7.2MHz\n\
+             forbid, 21.3MHz, 21.4MHz # 3rd harmonic of 7.1-7.133MHz\n\
+             dwell, 10kHz, 1ms\n"
+                .to_string(),
+        )
+        .unwrap();
+
+        let freqs = [
+            (0.0000, 7_050_000.0),
+            (0.0005, 7_052_000.0),
+            (0.0010, 7_055_000.0),
+            (0.0015, 7_110_000.0),
+            (0.0020, 7_300_000.0),
+            (0.0025, 7_010_000.0),
+        ];
+        let violations = plan.check(&freqs);
+
+        assert_eq!(violations.len(), 3);
+        // 7.050, 7.052 and 7.055MHz share a channel for 1.5ms
+        assert_eq!(violations[0].time_s, 0.0);
+        assert!(violations[1].msg.starts_with("Harmonic 3"));
+        assert_eq!(violations[2].freq_hz, 7_300_000.0);
+    }
+
+    #[test]
+    fn reports_error_position() {
+        let err =
+            parse_bandplan("allow, 7MHz, 7.2MHz\nforbid, 8MHz, 7MHz".to_string()).unwrap_err();
+        assert_eq!((err.line, err.column), (2, 15));
+
+        let err = parse_bandplan("dwell, 10kHz".to_string()).unwrap_err();
+        assert_eq!((err.line, err.column), (1, 13));
+
+        let err = parse_bandplan("harmonics, 0".to_string()).unwrap_err();
+        assert_eq!((err.line, err.column), (1, 12));
+    }
+}
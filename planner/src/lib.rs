@@ -0,0 +1,10 @@
+//! Generation of transmitted sequences from frequency orders, shared between the
+//! transmitter host (`software`) and the `receiver`, so that both regenerate the exact
+//! same hop schedule from the orders and start epoch.
+pub mod bandplan;
+pub mod orders;
+pub mod patterns;
+pub mod planfile;
+pub mod report;
+pub mod sequence;
+pub mod upload;
@@ -0,0 +1,473 @@
+//! Orders files are CSV files with a header row naming the columns, for example:
+//!
+//! ```text
+//! # Lines starting with # (and anything after a #) are comments
+//! type,      duration, freq,   bandwidth, start, stop,   hops, channels, seed, repeat
+//! random,    500ms,    7.1MHz, 100kHz,    ,      ,       1000
+//! chirp,     1s,       ,       ,          7MHz,  7.2MHz, 500
+//! welch,     200ms,    10MHz,  50kHz,     ,      ,       100,  100,      42,   3
+//! ```
+//!
+//! Columns may appear in any order and trailing empty values may be omitted. Durations
+//! accept `us`, `ms` and `s` suffixes (microseconds if none), and frequencies `Hz`, `kHz` and
+//! `MHz` (hertz if none). Random and hop pattern orders use `freq` and `bandwidth`, while
+//! chirps (`chirp` and the triangular `chirp_tri`) sweep from `start` to `stop`. `seed`
+//! overrides the time-derived seed, and `repeat` appends the order that many times.
+//!
+//! Files without a header row are read as `duration, freq, bandwidth, hops`.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+use crate::patterns::{self, HopPattern};
+
+/// Direction of a linear frequency sweep over the order band
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum ChirpShape {
+    /// From the bottom to the top of the band
+    Up,
+    /// From the top to the bottom of the band
+    Down,
+    /// Up during the first half of the order, down during the second
+    UpDown,
+    /// Down during the first half of the order, up during the second
+    DownUp,
+}
+
+/// How the frequencies of an order are distributed over its band
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum OrderKind {
+    /// Uniformly random hops within the band
+    Random,
+    /// Monotonic fracn ramp (FMCW) within the band
+    Chirp(ChirpShape),
+    /// Deterministic hop pattern over `channels` equally spaced channels
+    Pattern {
+        pattern: HopPattern,
+        channels: usize,
+    },
+}
+
+// The sequencer will generate a pseudo-random sequence that spends t_us
+// on band of width bandwidth_Hz centered around freq_Hz, with n frequency
+// changes.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FrequencyOrder {
+    pub t_us: u32,
+    pub freq_hz: u32,
+    pub bandwidth_hz: u32,
+    pub n: usize,
+    pub kind: OrderKind,
+    /// If set, used instead of the time-derived seed
+    pub seed: Option<u64>,
+}
+
+impl FrequencyOrder {
+    /// Builds a linear chirp sweeping from `start_hz` to `stop_hz` in `steps` steps
+    /// over `t_us`. If `triangular`, the sweep returns back to `start_hz` during the
+    /// second half of the order.
+    pub fn chirp(start_hz: u32, stop_hz: u32, t_us: u32, steps: usize, triangular: bool) -> Self {
+        let up = stop_hz >= start_hz;
+        let shape = match (up, triangular) {
+            (true, false) => ChirpShape::Up,
+            (false, false) => ChirpShape::Down,
+            (true, true) => ChirpShape::UpDown,
+            (false, true) => ChirpShape::DownUp,
+        };
+
+        let (low, high) = if up {
+            (start_hz, stop_hz)
+        } else {
+            (stop_hz, start_hz)
+        };
+
+        FrequencyOrder {
+            t_us,
+            freq_hz: low + (high - low) / 2,
+            bandwidth_hz: high - low,
+            n: steps,
+            kind: OrderKind::Chirp(shape),
+            seed: None,
+        }
+    }
+
+    /// Returns the (lowest, highest) frequency of the band of the order
+    pub fn band(&self) -> (f64, f64) {
+        let half = 0.5 * self.bandwidth_hz as f64;
+        (self.freq_hz as f64 - half, self.freq_hz as f64 + half)
+    }
+}
+
+/// Error while parsing an orders file, with 1-based line and column
+#[derive(Debug)]
+pub struct OrderParseError {
+    pub line: usize,
+    pub column: usize,
+    pub msg: String,
+}
+
+impl fmt::Display for OrderParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.msg
+        )
+    }
+}
+
+impl std::error::Error for OrderParseError {}
+
+const COLUMNS: [&str; 10] = [
+    "type",
+    "duration",
+    "freq",
+    "bandwidth",
+    "start",
+    "stop",
+    "hops",
+    "channels",
+    "seed",
+    "repeat",
+];
+
+const LEGACY_HEADER: [&str; 4] = ["duration", "freq", "bandwidth", "hops"];
+
+pub(crate) const FREQ_UNITS: [(&str, f64); 3] = [("Hz", 1.0), ("kHz", 1e3), ("MHz", 1e6)];
+pub(crate) const DURATION_UNITS: [(&str, f64); 3] = [("us", 1.0), ("ms", 1e3), ("s", 1e6)];
+
+/// A trimmed comma separated value and the column where it starts
+pub(crate) struct Field<'a> {
+    pub(crate) text: &'a str,
+    pub(crate) column: usize,
+}
+
+pub(crate) fn number_regex() -> Regex {
+    Regex::new(r"^([0-9]*\.?[0-9]+(?:[eE][-+]?[0-9]+)?)\s*([a-zA-Z]*)$").unwrap()
+}
+
+// Parses a number with an optional unit suffix, returning it in base units
+pub(crate) fn parse_quantity(
+    number: &Regex,
+    text: &str,
+    units: &[(&str, f64)],
+) -> Result<f64, String> {
+    let captures = number
+        .captures(text)
+        .ok_or_else(|| format!("Invalid number '{}'", text))?;
+    let value: f64 = captures[1].parse().unwrap();
+    let unit = &captures[2];
+
+    if unit.is_empty() {
+        return Ok(value);
+    }
+
+    units
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(unit))
+        .map(|(_, mult)| value * mult)
+        .ok_or_else(|| format!("Unknown unit '{}'", unit))
+}
+
+pub(crate) fn split_fields(line: &str) -> Vec<Field<'_>> {
+    let mut out = Vec::new();
+    let mut offset = 0;
+
+    for raw in line.split(',') {
+        let leading = raw.len() - raw.trim_start().len();
+        out.push(Field {
+            text: raw.trim(),
+            column: offset + leading + 1,
+        });
+        offset += raw.len() + 1;
+    }
+
+    out
+}
+
+/// Parser state for a single data row
+struct Row<'a> {
+    line: usize,
+    end_column: usize,
+    header: &'a [String],
+    fields: Vec<Field<'a>>,
+    number: &'a Regex,
+}
+
+impl Row<'_> {
+    fn error(&self, column: usize, msg: String) -> OrderParseError {
+        OrderParseError {
+            line: self.line,
+            column,
+            msg,
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&Field<'_>> {
+        let idx = self.header.iter().position(|h| h == name)?;
+        self.fields.get(idx).filter(|f| !f.text.is_empty())
+    }
+
+    fn required(&self, name: &str) -> Result<&Field<'_>, OrderParseError> {
+        self.get(name).ok_or_else(|| {
+            let column = self
+                .header
+                .iter()
+                .position(|h| h == name)
+                .and_then(|idx| self.fields.get(idx))
+                .map_or(self.end_column, |f| f.column);
+            self.error(column, format!("Missing value for '{}'", name))
+        })
+    }
+
+    fn quantity(&self, field: &Field, units: &[(&str, f64)]) -> Result<f64, OrderParseError> {
+        parse_quantity(self.number, field.text, units).map_err(|msg| self.error(field.column, msg))
+    }
+
+    fn freq_hz(&self, name: &str) -> Result<u32, OrderParseError> {
+        let field = self.required(name)?;
+        let hz = self.quantity(field, &FREQ_UNITS)?;
+        if !(0.0..=u32::MAX as f64).contains(&hz) {
+            return Err(self.error(field.column, format!("Frequency out of range '{}'", hz)));
+        }
+        Ok(hz.round() as u32)
+    }
+
+    fn duration_us(&self, name: &str) -> Result<u32, OrderParseError> {
+        let field = self.required(name)?;
+        let us = self.quantity(field, &DURATION_UNITS)?;
+        if !(0.0..=u32::MAX as f64).contains(&us) {
+            return Err(self.error(field.column, format!("Duration out of range '{}'", us)));
+        }
+        Ok(us.round() as u32)
+    }
+
+    fn integer<T: std::str::FromStr>(&self, field: &Field) -> Result<T, OrderParseError> {
+        field
+            .text
+            .parse()
+            .map_err(|_| self.error(field.column, format!("Invalid integer '{}'", field.text)))
+    }
+
+    fn positive(&self, field: &Field, name: &str) -> Result<usize, OrderParseError> {
+        let value = self.integer(field)?;
+        if value == 0 {
+            return Err(self.error(field.column, format!("'{}' must be positive", name)));
+        }
+        Ok(value)
+    }
+
+    fn count(&self, name: &str) -> Result<Option<usize>, OrderParseError> {
+        self.get(name).map(|f| self.positive(f, name)).transpose()
+    }
+
+    fn order(&self) -> Result<FrequencyOrder, OrderParseError> {
+        let t_us = self.duration_us("duration")?;
+        let n = self.positive(self.required("hops")?, "hops")?;
+
+        let kind_field = self.get("type");
+        let pattern = match kind_field.map_or("random", |f| f.text) {
+            "chirp" | "chirp_tri" => {
+                let triangular = kind_field.is_some_and(|f| f.text == "chirp_tri");
+                let start = self.freq_hz("start")?;
+                let stop = self.freq_hz("stop")?;
+                if start == stop {
+                    let column = self.required("stop")?.column;
+                    return Err(self.error(
+                        column,
+                        String::from("Chirp must not start and stop at the same frequency"),
+                    ));
+                }
+                return Ok(FrequencyOrder {
+                    seed: self.seed()?,
+                    ..FrequencyOrder::chirp(start, stop, t_us, n, triangular)
+                });
+            }
+            "random" => None,
+            "welch" => Some(HopPattern::WelchCostas),
+            "lempel_golomb" => Some(HopPattern::LempelGolombCostas),
+            "quadratic" => Some(HopPattern::QuadraticCongruence),
+            "mseq" => Some(HopPattern::MSequence),
+            other => {
+                let column = kind_field.map_or(1, |f| f.column);
+                return Err(self.error(column, format!("Unknown order type '{}'", other)));
+            }
+        };
+
+        let kind = match pattern {
+            None => OrderKind::Random,
+            Some(pattern) => {
+                let channels = self.count("channels")?.unwrap_or(n);
+                patterns::check_channels(pattern, channels).map_err(|msg| {
+                    // Channels default to the number of hops
+                    let field = self.get("channels").or(self.get("hops"));
+                    self.error(field.map_or(1, |f| f.column), String::from(msg))
+                })?;
+                OrderKind::Pattern { pattern, channels }
+            }
+        };
+
+        Ok(FrequencyOrder {
+            t_us,
+            freq_hz: self.freq_hz("freq")?,
+            bandwidth_hz: self.freq_hz("bandwidth")?,
+            n,
+            kind,
+            seed: self.seed()?,
+        })
+    }
+
+    fn seed(&self) -> Result<Option<u64>, OrderParseError> {
+        self.get("seed").map(|f| self.integer(f)).transpose()
+    }
+}
+
+pub fn parse_orders(file: String) -> Result<Vec<FrequencyOrder>, OrderParseError> {
+    let number = number_regex();
+    let mut header: Option<Vec<String>> = None;
+    let mut out: Vec<FrequencyOrder> = Vec::new();
+
+    for (i, full_line) in file.lines().enumerate() {
+        let line = full_line.split('#').next().unwrap_or_default();
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let fields = split_fields(line);
+
+        if header.is_none() {
+            if fields[0]
+                .text
+                .starts_with(|c: char| c.is_ascii_alphabetic())
+            {
+                // This is the header row
+                let mut names: Vec<String> = Vec::new();
+                for field in &fields {
+                    let name = field.text.to_ascii_lowercase();
+                    if !COLUMNS.contains(&name.as_str()) {
+                        return Err(OrderParseError {
+                            line: i + 1,
+                            column: field.column,
+                            msg: format!("Unknown column '{}'", field.text),
+                        });
+                    }
+                    if names.contains(&name) {
+                        return Err(OrderParseError {
+                            line: i + 1,
+                            column: field.column,
+                            msg: format!("Duplicated column '{}'", field.text),
+                        });
+                    }
+                    names.push(name);
+                }
+                header = Some(names);
+                continue;
+            }
+
+            header = Some(LEGACY_HEADER.iter().map(|s| s.to_string()).collect());
+        }
+        let header = header.as_ref().unwrap();
+
+        if let Some(extra) = fields.get(header.len()) {
+            return Err(OrderParseError {
+                line: i + 1,
+                column: extra.column,
+                msg: format!("Expected at most {} values", header.len()),
+            });
+        }
+
+        let row = Row {
+            line: i + 1,
+            end_column: line.trim_end().len() + 1,
+            header,
+            fields,
+            number: &number,
+        };
+
+        let order = row.order()?;
+        for _ in 0..row.count("repeat")?.unwrap_or(1) {
+            out.push(order.clone());
+        }
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_header_comments_units_and_repeats() {
+        let file = "# Test orders\n\
+                    \n\
+                    type, duration, freq, bandwidth, start, stop, hops, channels, seed, repeat\n\
+                    random, 500ms, 7.1MHz, 100kHz, , , 1000 # trailing comment\n\
+                    chirp, 1s, , , 7.2MHz, 7MHz, 500\n\
+                    welch, 200, 10MHz, 50kHz, , , 100, 100, 42, 3\n";
+        let orders = parse_orders(file.to_string()).unwrap();
+
+        assert_eq!(orders.len(), 5);
+        assert_eq!(orders[0].t_us, 500_000);
+        assert_eq!(orders[0].freq_hz, 7_100_000);
+        assert_eq!(orders[0].bandwidth_hz, 100_000);
+        assert_eq!(orders[0].kind, OrderKind::Random);
+        assert_eq!(orders[1].kind, OrderKind::Chirp(ChirpShape::Down));
+        assert_eq!(orders[1].freq_hz, 7_100_000);
+        assert_eq!(orders[1].bandwidth_hz, 200_000);
+        assert_eq!(orders[4].t_us, 200);
+        assert_eq!(orders[4].seed, Some(42));
+        assert_eq!(
+            orders[4].kind,
+            OrderKind::Pattern {
+                pattern: HopPattern::WelchCostas,
+                channels: 100
+            }
+        );
+    }
+
+    #[test]
+    fn parses_legacy_files() {
+        let orders = parse_orders("1000000, 7000000, 10000, 100\n".to_string()).unwrap();
+        assert_eq!(orders.len(), 1);
+        assert_eq!(orders[0].n, 100);
+    }
+
+    #[test]
+    fn reports_error_position() {
+        let err = parse_orders("duration, freq, bandwidth, hops\n10ms, 7MHz, 10GHz, 5".to_string())
+            .unwrap_err();
+        assert_eq!((err.line, err.column), (2, 13));
+
+        let err = parse_orders("duration, freq, bandwidth, hops\n10ms, 7MHz, 10kHz".to_string())
+            .unwrap_err();
+        assert_eq!((err.line, err.column), (2, 18));
+    }
+
+    #[test]
+    fn rejects_impossible_patterns() {
+        let header = "type, duration, freq, bandwidth, hops, channels\n";
+        let err = parse_orders(format!("{header}welch, 1s, 7MHz, 10kHz, 10, 11")).unwrap_err();
+        assert_eq!((err.line, err.column), (2, 29));
+
+        let err = parse_orders(format!("{header}lempel_golomb, 1s, 7MHz, 10kHz, 12")).unwrap_err();
+        assert_eq!((err.line, err.column), (2, 33));
+
+        assert!(parse_orders(format!("{header}welch, 1s, 7MHz, 10kHz, 10, 10")).is_ok());
+    }
+
+    #[test]
+    fn rejects_degenerate_chirps() {
+        let err = parse_orders(
+            "type, duration, start, stop, hops\nchirp, 1s, 7MHz, 7MHz, 10".to_string(),
+        )
+        .unwrap_err();
+        assert_eq!((err.line, err.column), (2, 18));
+
+        let err =
+            parse_orders("type, duration, start, stop, hops\nchirp, 1s, 7MHz, 8MHz, 0".to_string())
+                .unwrap_err();
+        assert_eq!((err.line, err.column), (2, 24));
+    }
+}
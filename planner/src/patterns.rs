@@ -0,0 +1,315 @@
+//! Deterministic hop orderings over a set of equally spaced channels. Compared to uniformly
+//! random hops, these have well known (and much cleaner) ambiguity functions.
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use serde::{Deserialize, Serialize};
+
+/// Structured hop pattern used to pick a channel on each hop of an order
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Serialize, Deserialize)]
+pub enum HopPattern {
+    /// Exponential Welch Costas array, needs channels + 1 to be prime
+    WelchCostas,
+    /// Lempel-Golomb Costas array, needs channels + 2 to be prime
+    LempelGolombCostas,
+    /// Quadratic congruence sequence, needs channels to be prime
+    QuadraticCongruence,
+    /// Channels indexed by the state of a maximal length LFSR, needs channels to divide its
+    /// period 2^n - 1 (for some n up to 20) so every channel is hit equally often
+    MSequence,
+}
+
+// Taps (1-indexed) of maximal length Fibonacci LFSRs, indexed by degree - 2.
+// Taken from Xilinx XAPP052.
+const LFSR_TAPS: [&[u32]; 19] = [
+    &[2, 1],
+    &[3, 2],
+    &[4, 3],
+    &[5, 3],
+    &[6, 5],
+    &[7, 6],
+    &[8, 6, 5, 4],
+    &[9, 5],
+    &[10, 7],
+    &[11, 9],
+    &[12, 6, 4, 1],
+    &[13, 4, 3, 1],
+    &[14, 5, 3, 1],
+    &[15, 14],
+    &[16, 15, 13, 4],
+    &[17, 14],
+    &[18, 11],
+    &[19, 6, 2, 1],
+    &[20, 17],
+];
+
+fn is_prime(n: u64) -> bool {
+    if n < 2 {
+        return false;
+    }
+    (2..)
+        .take_while(|d| d * d <= n)
+        .all(|d| !n.is_multiple_of(d))
+}
+
+fn pow_mod(base: u64, exp: u64, p: u64) -> u64 {
+    let mut out = 1;
+    let mut base = base % p;
+    let mut exp = exp;
+    while exp > 0 {
+        if exp & 1 == 1 {
+            out = out * base % p;
+        }
+        base = base * base % p;
+        exp >>= 1;
+    }
+    out
+}
+
+/// Returns all primitive roots modulo the prime `p`
+fn primitive_roots(p: u64) -> Vec<u64> {
+    let order = p - 1;
+    let factors: Vec<u64> = (2..=order)
+        .filter(|&d| order.is_multiple_of(d) && is_prime(d))
+        .collect();
+
+    (1..p)
+        .filter(|&g| factors.iter().all(|&f| pow_mod(g, order / f, p) != 1))
+        .collect()
+}
+
+/// Welch Costas array of size p - 1, a_i = g^(i + shift) mod p - 1, with g a primitive root
+/// of the prime `p`. Any cyclic shift of a Welch array is also a Costas array.
+pub fn welch_costas(p: u64, g: u64, shift: u64) -> Vec<usize> {
+    (0..p - 1)
+        .map(|i| (pow_mod(g, i + shift, p) - 1) as usize)
+        .collect()
+}
+
+/// Golomb Costas array of size q - 2, for the prime `q` and primitive roots `a` and `b`
+/// (Lempel's construction if `a` == `b`): a_i = j iff a^i + b^j = 1
+pub fn lempel_golomb_costas(q: u64, a: u64, b: u64) -> Vec<usize> {
+    // Discrete logarithm table for b
+    let mut log_b = vec![0; q as usize];
+    for j in 1..q - 1 {
+        log_b[pow_mod(b, j, q) as usize] = j;
+    }
+
+    (1..q - 1)
+        .map(|i| {
+            let target = (q + 1 - pow_mod(a, i, q)) % q;
+            (log_b[target as usize] - 1) as usize
+        })
+        .collect()
+}
+
+/// Quadratic congruence sequence of size `p`, y_i = (a i^2 + b i + c) mod p
+pub fn quadratic_congruence(p: u64, a: u64, b: u64, c: u64) -> Vec<usize> {
+    (0..p)
+        .map(|i| ((a * i % p * i + b * i + c) % p) as usize)
+        .collect()
+}
+
+/// Successive states (all of them non-zero) of a maximal length LFSR of the given degree,
+/// starting from the non-zero `state`. The sequence has period 2^degree - 1.
+pub fn m_sequence_states(degree: u32, state: u32) -> Vec<u32> {
+    let taps = LFSR_TAPS[degree as usize - 2];
+    let mask = (1u32 << degree) - 1;
+    let mut state = state & mask;
+    debug_assert!(state != 0);
+
+    (0..mask)
+        .map(|_| {
+            let out = state;
+            let feedback = taps.iter().fold(0, |acc, t| acc ^ ((state >> (t - 1)) & 1));
+            state = ((state << 1) | feedback) & mask;
+            out
+        })
+        .collect()
+}
+
+// Degree of the smallest LFSR whose period is a multiple of `channels`
+fn m_sequence_degree(channels: u64) -> Option<u32> {
+    (2..=LFSR_TAPS.len() as u32 + 1).find(|&m| ((1u64 << m) - 1).is_multiple_of(channels))
+}
+
+/// Checks that `pattern` can be built over `channels` channels
+pub fn check_channels(pattern: HopPattern, channels: usize) -> Result<(), &'static str> {
+    let c = channels as u64;
+
+    match pattern {
+        HopPattern::WelchCostas if !is_prime(c + 1) => {
+            Err("Welch Costas arrays need channels + 1 to be prime")
+        }
+        HopPattern::LempelGolombCostas if c < 2 || !is_prime(c + 2) => {
+            Err("Lempel-Golomb Costas arrays need channels + 2 to be prime")
+        }
+        HopPattern::QuadraticCongruence if !is_prime(c) => {
+            Err("Quadratic congruence sequences need channels to be prime")
+        }
+        HopPattern::MSequence if m_sequence_degree(c).is_none() => {
+            Err("m-sequences need channels to divide 2^n - 1, for some n up to 20")
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns `n` channel indices on [0, channels) following `pattern`, repeating its period as
+/// needed. The `seed` picks among the equivalent variants of the pattern (primitive roots,
+/// coefficients, cyclic shifts or LFSR initial state).
+pub fn hop_channels(
+    pattern: HopPattern,
+    channels: usize,
+    n: usize,
+    seed: u64,
+) -> Result<Vec<usize>, &'static str> {
+    check_channels(pattern, channels)?;
+    let mut rng = ChaCha20Rng::seed_from_u64(seed);
+    let c = channels as u64;
+
+    let period = match pattern {
+        HopPattern::WelchCostas => {
+            let roots = primitive_roots(c + 1);
+            let g = roots[rng.random_range(0..roots.len())];
+            welch_costas(c + 1, g, rng.random_range(0..c))
+        }
+        HopPattern::LempelGolombCostas => {
+            let roots = primitive_roots(c + 2);
+            let a = roots[rng.random_range(0..roots.len())];
+            let b = roots[rng.random_range(0..roots.len())];
+            lempel_golomb_costas(c + 2, a, b)
+        }
+        HopPattern::QuadraticCongruence => quadratic_congruence(
+            c,
+            rng.random_range(1..c),
+            rng.random_range(0..c),
+            rng.random_range(0..c),
+        ),
+        HopPattern::MSequence => {
+            let degree = m_sequence_degree(c).expect("Checked above");
+            let state = rng.random_range(1..(1u32 << degree));
+            m_sequence_states(degree, state)
+                .into_iter()
+                .map(|s| (s as usize - 1) % channels)
+                .collect()
+        }
+    };
+
+    Ok(period.into_iter().cycle().take(n).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A permutation is a Costas array iff all its displacement vectors are distinct
+    fn is_costas(array: &[usize]) -> bool {
+        let mut sorted = array.to_vec();
+        sorted.sort();
+        if sorted != (0..array.len()).collect::<Vec<_>>() {
+            return false;
+        }
+
+        let mut vectors = Vec::new();
+        for i in 0..array.len() {
+            for j in i + 1..array.len() {
+                vectors.push((j - i, array[j] as i64 - array[i] as i64));
+            }
+        }
+        let total = vectors.len();
+        vectors.sort();
+        vectors.dedup();
+        vectors.len() == total
+    }
+
+    #[test]
+    fn welch_arrays_are_costas() {
+        for p in [5, 7, 11, 13, 17, 31] {
+            for g in primitive_roots(p) {
+                for shift in 0..p - 1 {
+                    assert!(is_costas(&welch_costas(p, g, shift)), "p={p} g={g}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn lempel_golomb_arrays_are_costas() {
+        for q in [5, 7, 11, 13, 17, 31] {
+            let roots = primitive_roots(q);
+            for &a in &roots {
+                for &b in &roots {
+                    assert!(is_costas(&lempel_golomb_costas(q, a, b)), "q={q}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn quadratic_congruence_hits_once_per_shift() {
+        for p in [5, 7, 11, 13] {
+            let y = quadratic_congruence(p, 3 % p, 2, 1);
+            let p = p as usize;
+            // Any non-zero cyclic time shift coincides exactly once for every frequency shift
+            for dt in 1..p {
+                for df in 0..p {
+                    let hits = (0..p)
+                        .filter(|&i| (y[(i + dt) % p] + p - y[i]) % p == df)
+                        .count();
+                    assert_eq!(hits, 1, "p={p} dt={dt} df={df}");
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn m_sequences_are_maximal_and_balanced() {
+        for degree in 2..=LFSR_TAPS.len() as u32 + 1 {
+            let states = m_sequence_states(degree, 1);
+            let period = (1usize << degree) - 1;
+
+            // Every non-zero state is visited exactly once in a period
+            let mut sorted = states.clone();
+            sorted.sort();
+            sorted.dedup();
+            assert_eq!(sorted.len(), period, "degree={degree}");
+            assert_eq!(sorted[0], 1);
+
+            // Output bit stream has 2^(m-1) ones and 2^(m-1) - 1 zeros
+            let ones = states.iter().filter(|&s| s & 1 == 1).count();
+            assert_eq!(ones, 1 << (degree - 1), "degree={degree}");
+        }
+    }
+
+    #[test]
+    fn hop_channels_stay_in_range() {
+        let cases = [
+            (HopPattern::WelchCostas, 10),
+            (HopPattern::LempelGolombCostas, 11),
+            (HopPattern::QuadraticCongruence, 13),
+            (HopPattern::MSequence, 21),
+        ];
+        for (pattern, channels) in cases {
+            let hops = hop_channels(pattern, channels, 100, 42).unwrap();
+            assert_eq!(hops.len(), 100);
+            assert!(hops.iter().all(|&c| c < channels));
+        }
+
+        assert!(hop_channels(HopPattern::WelchCostas, 11, 10, 0).is_err());
+    }
+
+    #[test]
+    fn m_sequence_hops_are_balanced() {
+        // 7 divides 2^3 - 1, and 5 divides 2^4 - 1
+        for channels in [7, 5] {
+            let period = (1 << m_sequence_degree(channels as u64).unwrap()) - 1;
+            let hops = hop_channels(HopPattern::MSequence, channels, period, 3).unwrap();
+            for c in 0..channels {
+                let hits = hops.iter().filter(|&&h| h == c).count();
+                assert_eq!(hits, period / channels, "channels={channels} c={c}");
+            }
+        }
+
+        assert!(hop_channels(HopPattern::MSequence, 20, 10, 0).is_err());
+    }
+}
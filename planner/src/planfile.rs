@@ -0,0 +1,239 @@
+//! Upload plans saved to JSON files, so that past campaigns can be uploaded again or used as
+//! the reference on reception exactly as they were generated, even if the generation code
+//! changes in the meantime.
+
+use std::fmt;
+
+use common::sequence::{PLLChange, Sequence};
+use serde::{Deserialize, Serialize};
+
+use crate::orders::FrequencyOrder;
+use crate::sequence::{UploadPlan, order_seed};
+
+/// Bumped on any incompatible change to the file format
+pub const PLAN_FILE_VERSION: u32 = 1;
+
+/// Error while reading or building a plan file
+#[derive(Debug)]
+pub struct PlanFileError {
+    pub msg: String,
+}
+
+impl fmt::Display for PlanFileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.msg)
+    }
+}
+
+impl std::error::Error for PlanFileError {}
+
+/// A sequence of the plan, and when its upload starts relative to the start epoch
+#[derive(Serialize, Deserialize)]
+pub struct PlannedUpload {
+    pub upload_off_us: i64,
+    pub fracn: Vec<u16>,
+    pub pll_changes: Vec<PLLChange>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PlanFile {
+    pub version: u32,
+    /// Version of the program that generated the plan
+    pub software_version: String,
+    pub start_epoch: i64,
+    /// Frequency the PLL reference was calibrated to when the plan was generated
+    pub fref_hz: f64,
+    /// Offset of the host clock from true time when the plan was generated, if known
+    #[serde(default)]
+    pub clock_offset_s: Option<f64>,
+    /// Orders the plan was generated from, with the seed each of them used
+    pub orders: Vec<FrequencyOrder>,
+    pub uploads: Vec<PlannedUpload>,
+}
+
+impl PlanFile {
+    /// Stores a plan generated from `orders`. The secret is not stored, but the seeds it
+    /// produced are.
+    pub fn new(
+        software_version: &str,
+        orders: &[FrequencyOrder],
+        start_epoch: i64,
+        secret: Option<&[u8]>,
+        fref_hz: f64,
+        plan: &UploadPlan,
+    ) -> Self {
+        let orders = orders
+            .iter()
+            .enumerate()
+            .map(|(i, order)| FrequencyOrder {
+                seed: Some(order_seed(order, start_epoch, i, secret)),
+                ..order.clone()
+            })
+            .collect();
+
+        let uploads = plan
+            .iter()
+            .map(|(&upload_off_us, seq)| PlannedUpload {
+                upload_off_us,
+                fracn: seq.fracn_buffer.to_vec(),
+                pll_changes: seq.pllchange_buffer.to_vec(),
+            })
+            .collect();
+
+        PlanFile {
+            version: PLAN_FILE_VERSION,
+            software_version: String::from(software_version),
+            start_epoch,
+            fref_hz,
+            clock_offset_s: None,
+            orders,
+            uploads,
+        }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Plans are always serializable")
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, PlanFileError> {
+        let out: PlanFile = serde_json::from_str(json).map_err(|e| PlanFileError {
+            msg: format!("Invalid plan file, {}", e),
+        })?;
+
+        if out.version != PLAN_FILE_VERSION {
+            return Err(PlanFileError {
+                msg: format!(
+                    "Plan file version {} is not supported, expected version {}",
+                    out.version, PLAN_FILE_VERSION
+                ),
+            });
+        }
+
+        Ok(out)
+    }
+
+    /// Rebuilds the upload plan with the stored sequences, failing if there are none or they
+    /// are inconsistent (PLL changes past the end of their fracn, or repeated upload times)
+    pub fn upload_plan(&self) -> Result<UploadPlan, PlanFileError> {
+        if self.uploads.is_empty() {
+            return Err(PlanFileError {
+                msg: String::from("Plan file has no sequences"),
+            });
+        }
+
+        let mut out = UploadPlan::new();
+
+        for (i, upload) in self.uploads.iter().enumerate() {
+            let too_long = || PlanFileError {
+                msg: format!("Sequence {} of plan file doesn't fit in the transmitter", i),
+            };
+
+            let mut seq = Sequence::default();
+            seq.fracn_buffer
+                .extend_from_slice(&upload.fracn)
+                .map_err(|_| too_long())?;
+            seq.pllchange_buffer
+                .extend_from_slice(&upload.pll_changes)
+                .map_err(|_| too_long())?;
+
+            for change in &upload.pll_changes {
+                if change.start_tick + change.for_ticks > upload.fracn.len() {
+                    return Err(PlanFileError {
+                        msg: format!(
+                            "Sequence {} of plan file has a PLL change past its {} fracn",
+                            i,
+                            upload.fracn.len()
+                        ),
+                    });
+                }
+            }
+
+            if out.insert(upload.upload_off_us, seq).is_some() {
+                return Err(PlanFileError {
+                    msg: format!(
+                        "Sequence {} of plan file is uploaded at the same time as another one",
+                        i
+                    ),
+                });
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::parse_orders;
+    use crate::sequence::{FREF_HZ, build_frequencies, build_upload_plan};
+    use crate::upload::UploadModel;
+
+    #[test]
+    fn plan_survives_round_trip() {
+        let orders =
+            parse_orders("duration, freq, bandwidth, hops\n5s, 7MHz, 100kHz, 200\n".into())
+                .unwrap();
+        let plan =
+            build_upload_plan(&orders, 1_700_000_000, None, &UploadModel::default()).unwrap();
+
+        let file = PlanFile::new("test", &orders, 1_700_000_000, None, FREF_HZ, &plan);
+        let loaded = PlanFile::from_json(&file.to_json()).unwrap();
+        let loaded_plan = loaded.upload_plan().unwrap();
+
+        assert_eq!(loaded.orders[0].seed, file.orders[0].seed);
+        assert_eq!(
+            build_frequencies(plan.values(), 1_700_000_000),
+            build_frequencies(loaded_plan.values(), loaded.start_epoch)
+        );
+    }
+
+    #[test]
+    fn rejects_empty_plan() {
+        let orders =
+            parse_orders("duration, freq, bandwidth, hops\n5s, 7MHz, 100kHz, 200\n".into())
+                .unwrap();
+        let file = PlanFile::new(
+            "test",
+            &orders,
+            1_700_000_000,
+            None,
+            FREF_HZ,
+            &UploadPlan::new(),
+        );
+
+        assert!(file.upload_plan().is_err());
+    }
+
+    fn single_upload_file() -> PlanFile {
+        let orders =
+            parse_orders("duration, freq, bandwidth, hops\n5s, 7MHz, 100kHz, 200\n".into())
+                .unwrap();
+        let plan =
+            build_upload_plan(&orders, 1_700_000_000, None, &UploadModel::default()).unwrap();
+        PlanFile::new("test", &orders, 1_700_000_000, None, FREF_HZ, &plan)
+    }
+
+    #[test]
+    fn rejects_pll_change_past_fracn() {
+        let mut file = single_upload_file();
+        assert!(file.upload_plan().is_ok());
+
+        file.uploads[0].pll_changes[0].for_ticks += 1;
+        assert!(file.upload_plan().is_err());
+    }
+
+    #[test]
+    fn rejects_repeated_upload_times() {
+        let mut file = single_upload_file();
+        let upload = &file.uploads[0];
+        let copy = PlannedUpload {
+            upload_off_us: upload.upload_off_us,
+            fracn: upload.fracn.clone(),
+            pll_changes: upload.pll_changes.clone(),
+        };
+        file.uploads.push(copy);
+
+        assert!(file.upload_plan().is_err());
+    }
+}
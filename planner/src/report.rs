@@ -0,0 +1,237 @@
+//! Statistics of how far the frequencies actually emitted are from the ones requested by
+//! the orders, so an order can be checked before keying the transmitter.
+
+use std::fmt::Write;
+
+use crate::orders::FrequencyOrder;
+use crate::sequence::{build_subsequences, order_seed, pll_output_hz, unclamped_fracn};
+
+/// A single hop, as requested and as it will be emitted
+pub struct HopQuantization {
+    /// Index of the order the hop belongs to
+    pub order: usize,
+    pub requested_hz: f64,
+    pub achieved_hz: f64,
+    /// fracn was out of range and had to be clamped
+    pub clamped: bool,
+}
+
+/// Quantization statistics of a set of hops
+pub struct QuantizationStats {
+    pub hops: usize,
+    /// Band requested by the order(s)
+    pub band_low_hz: f64,
+    pub band_high_hz: f64,
+    /// Band actually occupied by the emitted frequencies
+    pub occupied_low_hz: f64,
+    pub occupied_high_hz: f64,
+    /// Number of distinct frequencies emitted
+    pub distinct: usize,
+    pub max_err_hz: f64,
+    pub rms_err_hz: f64,
+    pub clamped: usize,
+}
+
+impl QuantizationStats {
+    fn new<'a>(hops: impl Iterator<Item = &'a HopQuantization>, band: (f64, f64)) -> Self {
+        let mut out = QuantizationStats {
+            hops: 0,
+            band_low_hz: band.0,
+            band_high_hz: band.1,
+            occupied_low_hz: f64::INFINITY,
+            occupied_high_hz: f64::NEG_INFINITY,
+            distinct: 0,
+            max_err_hz: 0.0,
+            rms_err_hz: 0.0,
+            clamped: 0,
+        };
+
+        let mut achieved = Vec::new();
+        let mut sum_sq = 0.0;
+        for hop in hops {
+            let err = (hop.achieved_hz - hop.requested_hz).abs();
+            out.hops += 1;
+            out.max_err_hz = out.max_err_hz.max(err);
+            sum_sq += err * err;
+            out.clamped += hop.clamped as usize;
+            out.occupied_low_hz = out.occupied_low_hz.min(hop.achieved_hz);
+            out.occupied_high_hz = out.occupied_high_hz.max(hop.achieved_hz);
+            achieved.push(hop.achieved_hz);
+        }
+
+        if out.hops > 0 {
+            out.rms_err_hz = (sum_sq / out.hops as f64).sqrt();
+        }
+        achieved.sort_by(f64::total_cmp);
+        achieved.dedup();
+        out.distinct = achieved.len();
+
+        out
+    }
+
+    /// Fraction of the requested band spanned by the emitted frequencies
+    pub fn occupancy(&self) -> f64 {
+        if self.hops == 0 {
+            return 0.0;
+        }
+        (self.occupied_high_hz - self.occupied_low_hz) / (self.band_high_hz - self.band_low_hz)
+    }
+
+    fn summary_line(&self) -> String {
+        format!(
+            "{} hops, max error {:.3}Hz, RMS error {:.3}Hz, {} clamped, {} distinct \
+             frequencies over {:.0}-{:.0}Hz ({:.1}% of requested {:.0}-{:.0}Hz)",
+            self.hops,
+            self.max_err_hz,
+            self.rms_err_hz,
+            self.clamped,
+            self.distinct,
+            self.occupied_low_hz,
+            self.occupied_high_hz,
+            self.occupancy() * 100.0,
+            self.band_low_hz,
+            self.band_high_hz,
+        )
+    }
+}
+
+/// Requested versus achievable frequencies of every hop of a set of orders
+pub struct QuantizationReport {
+    pub hops: Vec<HopQuantization>,
+    pub orders: Vec<QuantizationStats>,
+    pub overall: QuantizationStats,
+}
+
+impl QuantizationReport {
+    /// Builds the report for the orders of a sequence starting at `start_epoch`, using the
+    /// same seeds as `build_upload_plan`
+    pub fn new(
+        orders: &[FrequencyOrder],
+        start_epoch: i64,
+        secret: Option<&[u8]>,
+    ) -> Result<Self, &'static str> {
+        let mut hops = Vec::new();
+
+        for (i, order) in orders.iter().enumerate() {
+            let seed = order_seed(order, start_epoch, i, secret);
+            for subseq in build_subsequences(order, seed)? {
+                let change = subseq.change;
+                for (&fracn, &requested_hz) in subseq.fracn.iter().zip(&subseq.requested_hz) {
+                    let unclamped = unclamped_fracn(requested_hz, change.divn, change.divp);
+                    hops.push(HopQuantization {
+                        order: i,
+                        requested_hz,
+                        achieved_hz: pll_output_hz(change.divn, change.divp, fracn),
+                        clamped: unclamped != fracn as f64,
+                    });
+                }
+            }
+        }
+
+        let order_stats = orders
+            .iter()
+            .enumerate()
+            .map(|(i, order)| {
+                QuantizationStats::new(hops.iter().filter(|h| h.order == i), order.band())
+            })
+            .collect();
+
+        let overall_band = orders
+            .iter()
+            .map(|o| o.band())
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |acc, band| {
+                (acc.0.min(band.0), acc.1.max(band.1))
+            });
+        let overall = QuantizationStats::new(hops.iter(), overall_band);
+
+        Ok(QuantizationReport {
+            hops,
+            orders: order_stats,
+            overall,
+        })
+    }
+
+    /// Human readable summary, one line per order and a final one for all of them
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+
+        for (i, stats) in self.orders.iter().enumerate() {
+            writeln!(&mut out, "Order {}: {}", i, stats.summary_line()).unwrap();
+        }
+        writeln!(&mut out, "Overall: {}", self.overall.summary_line()).unwrap();
+
+        out
+    }
+
+    /// CSV with a row per hop
+    pub fn to_csv(&self) -> String {
+        let mut out = String::from("order,requested_hz,achieved_hz,error_hz,clamped\n");
+
+        for hop in &self.hops {
+            writeln!(
+                &mut out,
+                "{},{:.6},{:.6},{:.6},{}",
+                hop.order,
+                hop.requested_hz,
+                hop.achieved_hz,
+                hop.achieved_hz - hop.requested_hz,
+                hop.clamped as u8
+            )
+            .unwrap();
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::orders::parse_orders;
+
+    fn hop(requested_hz: f64, achieved_hz: f64, clamped: bool) -> HopQuantization {
+        HopQuantization {
+            order: 0,
+            requested_hz,
+            achieved_hz,
+            clamped,
+        }
+    }
+
+    #[test]
+    fn stats_of_known_hops() {
+        let hops = [
+            hop(1000.0, 1003.0, false),
+            hop(2000.0, 1996.0, true),
+            hop(3000.0, 3003.0, false),
+        ];
+        let stats = QuantizationStats::new(hops.iter(), (0.0, 4000.0));
+
+        assert_eq!(stats.hops, 3);
+        assert_eq!(stats.max_err_hz, 4.0);
+        assert!((stats.rms_err_hz - (34.0f64 / 3.0).sqrt()).abs() < 1e-9);
+        assert_eq!(stats.clamped, 1);
+        assert_eq!(stats.distinct, 3);
+        assert!((stats.occupancy() - 2000.0 / 4000.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn chirp_error_is_within_fracn_step() {
+        let orders = parse_orders(String::from(
+            "type, duration, start, stop, hops\nchirp, 1s, 7MHz, 7.01MHz, 100",
+        ))
+        .unwrap();
+        let report = QuantizationReport::new(&orders, 1_700_000_000, None).unwrap();
+
+        let change = build_subsequences(&orders[0], 0).unwrap()[0].change;
+        let step_hz =
+            pll_output_hz(change.divn, change.divp, 1) - pll_output_hz(change.divn, change.divp, 0);
+
+        let stats = &report.overall;
+        assert_eq!(stats.hops, 100);
+        assert_eq!(stats.clamped, 0);
+        assert!(stats.max_err_hz <= step_hz / 2.0 + 1e-6);
+        assert!(stats.rms_err_hz <= stats.max_err_hz);
+        assert_eq!(report.to_csv().lines().count(), 101);
+    }
+}
@@ -0,0 +1,710 @@
+use std::collections::BTreeMap;
+
+use crate::orders::{ChirpShape, FrequencyOrder, OrderKind};
+use crate::patterns;
+use crate::upload::{TimedSequence, UploadModel, schedule_uploads};
+use common::sequence::{MAX_DIVN_CHANGES, MAX_SEQUENCE_LEN, PLLChange, Sequence};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+/// Nominal frequency of the PLL reference
+pub const FREF_HZ: f64 = 12_000_000.0;
+
+/// Time the transmitter takes to apply a PLLChange, during which the output is disabled
+pub const PLLCHANGE_US: f64 = 5.0;
+
+/// Max number of sub-bands an order too wide for a single PLL configuration is split in
+const MAX_SPLIT_BANDS: usize = 16;
+/// Number of times each sub-band of a split order is revisited along the order
+const SPLIT_ROUNDS: usize = 4;
+
+/// Sequences are seeded from their start epoch rounded down to a multiple of this many seconds
+pub const TIME_SEED_ROUND_S: i64 = 10;
+
+/// Maps UNIX timestamps to the sequence that must be uploaded at that moment
+pub type UploadPlan = BTreeMap<i64, Sequence>;
+
+pub struct SubSequence {
+    pub(crate) change: PLLChange,
+    pub(crate) fracn: Vec<u16>,
+    /// Frequency each fracn was computed for, before quantization
+    pub(crate) requested_hz: Vec<f64>,
+}
+
+const VCOSEL0_MIN_FREQ: f64 = 384_000_000.0;
+const VCOSEL0_MAX_FREQ: f64 = 1_672_000_000.0;
+const VCOSEL1_MIN_FREQ: f64 = 150_000_000.0;
+const VCOSEL1_MAX_FREQ: f64 = 420_000_000.0;
+/// (vcosel, min, max) VCO frequency for each VCO
+const VCO_RANGES: [(bool, f64, f64); 2] = [
+    (true, VCOSEL1_MIN_FREQ, VCOSEL1_MAX_FREQ),
+    (false, VCOSEL0_MIN_FREQ, VCOSEL0_MAX_FREQ),
+];
+
+/// Candidates closer than this (as a fraction of the width covered by fracn) to an integer
+/// boundary are only used if nothing else works, as the sigma-delta spurs are worst there
+const MIN_SPUR_DISTANCE: f64 = 0.05;
+/// Candidates with the VCO closer than this (as a fraction of its range) to its limits are
+/// only used if nothing else works
+const MIN_VCO_MARGIN: f64 = 0.01;
+
+/// Divider configuration of the PLL covering a band with fracn alone, and the figures of
+/// merit it was chosen by
+#[derive(Clone, Debug)]
+pub struct DividerChoice {
+    /// Lowest frequency of the band covered
+    pub flow_hz: f64,
+    /// Highest frequency of the band covered
+    pub fhigh_hz: f64,
+    pub divn: u16,
+    pub divp: u8,
+    pub vcosel: bool,
+    /// Frequency step of one fracn unit
+    pub resolution_hz: f64,
+    /// Worst-case distance from a frequency in the band to the nearest achievable one
+    pub max_quant_err_hz: f64,
+    /// Distance from the VCO frequencies to the VCO limits, as a fraction of its range
+    pub vco_margin: f64,
+    /// Distance from the band to the nearest integer-N frequency (fracn = 0 or 8192)
+    pub spur_distance_hz: f64,
+    /// Number of valid configurations considered
+    pub candidates: usize,
+    /// Human readable explanation of why this configuration won
+    pub reason: String,
+}
+
+impl DividerChoice {
+    // Width of the band that fracn can cover with this configuration
+    fn slot_hz(&self) -> f64 {
+        FREF_HZ / (self.divp as f64 + 1.0)
+    }
+
+    fn is_clean(&self) -> bool {
+        self.spur_distance_hz >= MIN_SPUR_DISTANCE * self.slot_hz()
+            && self.vco_margin >= MIN_VCO_MARGIN
+    }
+}
+
+// Returns the divn whose fracn range covers flow for the given divp + 1, if valid. Note that
+// integer-N frequencies are taken as the bottom of the slot above them.
+fn slot_divn(flow: f64, div: f64) -> Option<f64> {
+    let divn = (flow * div / FREF_HZ + 1e-9).floor() - 1.0;
+    (7.0..=419.0).contains(&divn).then_some(divn)
+}
+
+/// Searches all the valid divn, divp and vcosel combinations able to cover the band
+/// with fracn alone, and chooses the one with the finest resolution among those with the
+/// band clear from integer boundary spurs and the VCO clear from its limits. Ties are broken
+/// by spur distance and then by VCO margin.
+pub fn choose_dividers(flow: f64, fhigh: f64) -> Result<DividerChoice, &'static str> {
+    if fhigh <= flow || flow <= 0.0 {
+        return Err("Invalid configuration");
+    }
+
+    let mut candidates = Vec::new();
+    for divp in 0..=127u8 {
+        let div = divp as f64 + 1.0;
+        // fout = fref * (divn + 1 + fracn/2^13) / (divp + 1), so only one divn may work
+        let Some(divn) = slot_divn(flow, div) else {
+            continue;
+        };
+        let slot_low = FREF_HZ * (divn + 1.0) / div;
+        let slot_high = FREF_HZ * (divn + 2.0) / div;
+        // fracn tops at 8191, so the very top of the slot clamps (at most a step off)
+        if fhigh > slot_high {
+            continue;
+        }
+
+        let (vco_low, vco_high) = (flow * div, fhigh * div);
+        for (vcosel, vmin, vmax) in VCO_RANGES {
+            if vco_low < vmin || vco_high > vmax {
+                continue;
+            }
+
+            let resolution_hz = FREF_HZ / 8192.0 / div;
+            candidates.push(DividerChoice {
+                flow_hz: flow,
+                fhigh_hz: fhigh,
+                divn: divn as u16,
+                divp,
+                vcosel,
+                resolution_hz,
+                max_quant_err_hz: resolution_hz / 2.0,
+                vco_margin: (vco_low - vmin).min(vmax - vco_high) / (vmax - vmin),
+                spur_distance_hz: (flow - slot_low).min(slot_high - fhigh),
+                candidates: 0,
+                reason: String::new(),
+            });
+        }
+    }
+
+    let num_candidates = candidates.len();
+    let num_clean = candidates.iter().filter(|c| c.is_clean()).count();
+
+    candidates.sort_by(|a, b| {
+        b.is_clean()
+            .cmp(&a.is_clean())
+            .then(a.resolution_hz.total_cmp(&b.resolution_hz))
+            .then(b.spur_distance_hz.total_cmp(&a.spur_distance_hz))
+            .then(b.vco_margin.total_cmp(&a.vco_margin))
+    });
+
+    let mut iter = candidates.into_iter();
+    let mut best = iter
+        .next()
+        .ok_or("No divider configuration satisfies desired frequency range")?;
+    let runner_up = iter.next();
+
+    best.candidates = num_candidates;
+    best.reason = if best.is_clean() {
+        format!(
+            "finest resolution ({:.3}Hz) of {} candidates with the band at least {:.0}% of the \
+             fracn range away from integer spurs and the VCO {:.0}% away from its limits",
+            best.resolution_hz,
+            num_clean,
+            MIN_SPUR_DISTANCE * 100.0,
+            MIN_VCO_MARGIN * 100.0,
+        )
+    } else {
+        format!(
+            "none of the {} candidates keeps clear of integer spurs and VCO limits, chose \
+             the finest resolution ({:.3}Hz) with spurs {:.0}Hz away",
+            num_candidates, best.resolution_hz, best.spur_distance_hz
+        )
+    };
+    if let Some(runner_up) = runner_up {
+        best.reason += &format!(
+            "; runner up divp = {} has resolution {:.3}Hz, spurs {:.0}Hz away, VCO margin {:.1}%",
+            runner_up.divp,
+            runner_up.resolution_hz,
+            runner_up.spur_distance_hz,
+            runner_up.vco_margin * 100.0
+        );
+    }
+
+    Ok(best)
+}
+
+/// Output frequency of the PLL for the given dividers and fracn
+pub fn pll_output_hz(divn: u16, divp: u8, fracn: u16) -> f64 {
+    pll_output_hz_fref(FREF_HZ, divn, divp, fracn)
+}
+
+/// Same as `pll_output_hz`, for a reference measured to be at `fref_hz` instead of nominal
+pub fn pll_output_hz_fref(fref_hz: f64, divn: u16, divp: u8, fracn: u16) -> f64 {
+    fref_hz * (divn as f64 + 1.0 + fracn as f64 / 8192.0) / (divp as f64 + 1.0)
+}
+
+// Returns the (rounded but not clamped) fracn that gets closest to freq
+pub(crate) fn unclamped_fracn(freq: f64, divn: u16, divp: u8) -> f64 {
+    // Actual fout = fref * (divn + 1 + fracn/2^13) / (divp + 1), so we find
+    (8192.0 * (freq * (divp as f64 + 1.0) / FREF_HZ - (divn as f64 + 1.0))).round()
+}
+
+fn freq_to_fracn(freq: f64, choice: &DividerChoice) -> u16 {
+    let fracnf = unclamped_fracn(freq, choice.divn, choice.divp);
+    if fracnf < 0.0 {
+        log::warn!("fracn went below 0, clamping");
+        0
+    } else if fracnf >= 8192.0 {
+        log::warn!("fracn went above 8191, clamping");
+        8191
+    } else {
+        fracnf as u16
+    }
+}
+
+// Returns the desired position of each hop in the band, on [-0.5, 0.5)
+fn hop_positions(order: &FrequencyOrder, seed: u64) -> Result<Vec<f64>, &'static str> {
+    let n = order.n as f64;
+
+    Ok(match order.kind {
+        OrderKind::Random => {
+            let mut rng = ChaCha20Rng::seed_from_u64(seed);
+            // fac is uniformly distributed on [-0.5, 0.5)
+            (0..order.n).map(|_| rng.random::<f64>() - 0.5).collect()
+        }
+        OrderKind::Chirp(shape) => (0..order.n)
+            .map(|i| {
+                // Steps are centered on each of the n slices of the band, so the ramp never
+                // touches the band edges (where fracn would clamp)
+                let p = (i as f64 + 0.5) / n;
+                let ramp = match shape {
+                    ChirpShape::Up => p,
+                    ChirpShape::Down => 1.0 - p,
+                    ChirpShape::UpDown => 1.0 - (2.0 * p - 1.0).abs(),
+                    ChirpShape::DownUp => (2.0 * p - 1.0).abs(),
+                };
+                ramp - 0.5
+            })
+            .collect(),
+        OrderKind::Pattern { pattern, channels } => {
+            // Each hop lands on the center of its channel
+            patterns::hop_channels(pattern, channels, order.n, seed)?
+                .into_iter()
+                .map(|c| (c as f64 + 0.5) / channels as f64 - 0.5)
+                .collect()
+        }
+    })
+}
+
+// Returns the highest frequency (up to fhigh) reachable by any divider configuration
+// covering flow with fracn alone
+fn widest_band_from(flow: f64, fhigh: f64) -> Option<f64> {
+    let mut best: Option<f64> = None;
+
+    for divp in 0..=127u8 {
+        let div = divp as f64 + 1.0;
+        let Some(divn) = slot_divn(flow, div) else {
+            continue;
+        };
+        let slot_high = FREF_HZ * (divn + 2.0) / div;
+
+        for (_, vmin, vmax) in VCO_RANGES {
+            if flow * div < vmin {
+                continue;
+            }
+            let top = fhigh.min(slot_high).min(vmax / div);
+            if top > flow && best.is_none_or(|b| top > b) {
+                best = Some(top);
+            }
+        }
+    }
+
+    best
+}
+
+/// Covers the band with a single divider configuration if possible. Otherwise, the band is
+/// split greedily in the least number of sub-bands, each as wide as a divider configuration
+/// allows, which are then given the best configuration for them.
+pub fn split_band(flow: f64, fhigh: f64) -> Result<Vec<DividerChoice>, &'static str> {
+    if fhigh <= flow || flow <= 0.0 {
+        return Err("Invalid configuration");
+    }
+    if let Ok(choice) = choose_dividers(flow, fhigh) {
+        return Ok(vec![choice]);
+    }
+
+    let mut out = Vec::new();
+    let mut low = flow;
+    while low < fhigh {
+        if out.len() == MAX_SPLIT_BANDS {
+            return Err("Band too wide, it needs too many divider configurations");
+        }
+        let high = widest_band_from(low, fhigh)
+            .ok_or("No VCO configuration satisfies desired frequency range")?;
+        out.push(choose_dividers(low, high)?);
+        low = high;
+    }
+
+    println!("Band {}-{}Hz split in {} sub-bands", flow, fhigh, out.len());
+    Ok(out)
+}
+
+/// Builds the subsequences of an order. Usually this is a single one, but orders too wide
+/// for a single PLL configuration are split in sub-bands, each needing its own PLLChange.
+/// Random and chirp hops are then grouped by sub-band in `SPLIT_ROUNDS` rounds, so all
+/// sub-bands are visited all along the order while keeping the number of PLLChanges low.
+/// Pattern hops keep their order, as regrouping them would break the pattern, so they need
+/// a PLLChange every time they move to another sub-band.
+pub fn build_subsequences(
+    order: &FrequencyOrder,
+    seed: u64,
+) -> Result<Vec<SubSequence>, &'static str> {
+    let (flow, fhigh) = order.band();
+    let choices = split_band(flow, fhigh)?;
+    let k = choices.len();
+
+    let positions = hop_positions(order, seed)?;
+    let hop_band = |fac: f64| {
+        let freq = order.freq_hz as f64 + fac * (order.bandwidth_hz as f64);
+        choices
+            .iter()
+            .position(|c| freq < c.fhigh_hz)
+            .unwrap_or(k - 1)
+    };
+
+    // (sub-band, hops) for each run of hops sharing a PLL configuration
+    let mut runs: Vec<(usize, Vec<f64>)> = Vec::new();
+    if let OrderKind::Pattern { .. } = order.kind {
+        for fac in positions {
+            let band = hop_band(fac);
+            match runs.last_mut() {
+                Some((last, hops)) if *last == band => hops.push(fac),
+                _ => runs.push((band, vec![fac])),
+            }
+        }
+        if runs.len() > MAX_DIVN_CHANGES {
+            return Err(
+                "Pattern order changes sub-band too often, narrow its band or use fewer hops",
+            );
+        }
+    } else {
+        let rounds = if k == 1 {
+            1
+        } else {
+            SPLIT_ROUNDS.min(MAX_DIVN_CHANGES / k).min(order.n).max(1)
+        };
+        for r in 0..rounds {
+            let round = &positions[r * order.n / rounds..(r + 1) * order.n / rounds];
+            for band in 0..k {
+                let hops: Vec<f64> = round
+                    .iter()
+                    .copied()
+                    .filter(|&fac| hop_band(fac) == band)
+                    .collect();
+                if !hops.is_empty() {
+                    runs.push((band, hops));
+                }
+            }
+        }
+    }
+
+    // The extra PLLChanges eat into the order time, keep its total duration
+    let extra_us = (runs.len() - 1) as f64 * PLLCHANGE_US;
+    let tim_us = ((order.t_us as f64 - extra_us) / order.n as f64) as u32;
+    if tim_us == 0 {
+        return Err("Order too short for its hops and PLL changes");
+    }
+
+    Ok(runs
+        .into_iter()
+        .map(|(band, hops)| {
+            let choice = &choices[band];
+            let requested_hz: Vec<f64> = hops
+                .into_iter()
+                .map(|fac| order.freq_hz as f64 + fac * (order.bandwidth_hz as f64))
+                .collect();
+            SubSequence {
+                change: PLLChange {
+                    for_ticks: requested_hz.len(),
+                    start_tick: 0,
+                    divn: choice.divn,
+                    vcosel: choice.vcosel,
+                    divp: choice.divp,
+                    tim_us,
+                },
+
+                fracn: requested_hz
+                    .iter()
+                    .map(|&freq| freq_to_fracn(freq, choice))
+                    .collect(),
+                requested_hz,
+            }
+        })
+        .collect())
+}
+
+// Appends the sequence to the given one, or creates a new one if it doesn't fit and
+// returns the final sequence to be stored in the upload map. Fails if the order can't be
+// built, or doesn't fit on its own in a sequence.
+pub fn build_sequence(
+    order: &FrequencyOrder,
+    base: &mut Sequence,
+    seed: u64,
+) -> Result<Option<Sequence>, String> {
+    let mut out = None;
+
+    let subseqs = build_subsequences(order, seed)?;
+    let fracn_len: usize = subseqs.iter().map(|s| s.fracn.len()).sum();
+    if fracn_len > MAX_SEQUENCE_LEN {
+        return Err(format!(
+            "Order has {} hops, but at most {} fit in a sequence",
+            fracn_len, MAX_SEQUENCE_LEN
+        ));
+    }
+    if subseqs.len() > MAX_DIVN_CHANGES {
+        return Err(format!(
+            "Order needs {} PLL changes, but at most {} fit in a sequence",
+            subseqs.len(),
+            MAX_DIVN_CHANGES
+        ));
+    }
+
+    if base.fracn_buffer.len() + fracn_len > MAX_SEQUENCE_LEN
+        || base.pllchange_buffer.len() + subseqs.len() > MAX_DIVN_CHANGES
+    {
+        // We ran out of space in the base seq, create a new one
+        out = Some(base.expensive_copy());
+        *base = Default::default();
+    }
+
+    for mut subseq in subseqs {
+        // Offset PLLChange index!
+        subseq.change.start_tick += base.fracn_buffer.len();
+        base.pllchange_buffer
+            .push(subseq.change)
+            .unwrap_or_else(|_| panic!());
+
+        for fracni in subseq.fracn {
+            base.fracn_buffer.push(fracni).unwrap();
+        }
+    }
+
+    Ok(out)
+}
+
+/// Splits the orders into the sequences that fit in the transmitter. start_tstamp is the
+/// (approximate) time the sequence will start, secret is optionally mixed into the seed of
+/// every order (see derive_seed). Fails with the first order that can't be built.
+pub fn build_sequences(
+    orders: &[FrequencyOrder],
+    start_tstamp: i64,
+    secret: Option<&[u8]>,
+) -> Result<Vec<TimedSequence>, String> {
+    let mut out = Vec::new();
+
+    let mut work_seq: Sequence = Default::default();
+    let mut first_order = 0;
+    let mut toff_us: u64 = 0;
+    let mut step_us = 0;
+
+    for (i, order) in orders.iter().enumerate() {
+        let seed = order_seed(order, start_tstamp, i, secret);
+        let maybe_done = build_sequence(order, &mut work_seq, seed)
+            .map_err(|e| format!("Order {}: {}", i, e))?;
+
+        if let Some(done_seq) = maybe_done {
+            out.push(TimedSequence {
+                toff_us,
+                orders: first_order..i,
+                seq: done_seq,
+            });
+            first_order = i;
+            toff_us += step_us;
+            step_us = 0;
+        }
+        step_us += order.t_us as u64;
+    }
+
+    out.push(TimedSequence {
+        toff_us,
+        orders: first_order..orders.len(),
+        seq: work_seq,
+    });
+
+    Ok(out)
+}
+
+/// Builds the sequences of the orders and schedules their uploads, failing if any of them
+/// can't be built or uploaded in time with the given model
+pub fn build_upload_plan(
+    orders: &[FrequencyOrder],
+    start_tstamp: i64,
+    secret: Option<&[u8]>,
+    model: &UploadModel,
+) -> Result<UploadPlan, String> {
+    let seqs = build_sequences(orders, start_tstamp, secret)?;
+    schedule_uploads(seqs, model).map_err(|e| format!("Cannot upload orders in time, {}", e))
+}
+
+pub fn find_start_epoch(date: chrono::DateTime<chrono::Utc>) -> i64 {
+    // We add a bit of margin, to prevent the hypothetical case of starting a few milliseconds
+    // before the next epoch and not having enough time to send the stuff to the transmitter
+
+    const MARGIN_S: i64 = 1;
+    ((date.timestamp() + MARGIN_S) / TIME_SEED_ROUND_S) * TIME_SEED_ROUND_S + TIME_SEED_ROUND_S
+}
+
+/// Seed used for the `order_index`th order of a sequence, its own if given or the one
+/// derived from the start epoch otherwise
+pub fn order_seed(
+    order: &FrequencyOrder,
+    start_epoch: i64,
+    order_index: usize,
+    secret: Option<&[u8]>,
+) -> u64 {
+    order
+        .seed
+        .unwrap_or_else(|| derive_seed(start_epoch, order_index, secret))
+}
+
+/// Derives the seed of the `order_index`th order of a sequence starting at `start_epoch`.
+/// The epoch is rounded down to a multiple of `TIME_SEED_ROUND_S`, so the seed doesn't depend
+/// on a very precise clock, and the optional shared `secret` is mixed in so that only parties
+/// knowing it can regenerate the sequence. This is stable across platforms and versions,
+/// as receivers rely on it to regenerate the same hop pattern.
+pub fn derive_seed(start_epoch: i64, order_index: usize, secret: Option<&[u8]>) -> u64 {
+    let rounded_epoch = start_epoch - start_epoch.rem_euclid(TIME_SEED_ROUND_S);
+
+    // FNV-1a over all inputs...
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let bytes = rounded_epoch
+        .to_le_bytes()
+        .into_iter()
+        .chain((order_index as u64).to_le_bytes())
+        .chain(secret.unwrap_or_default().iter().copied());
+    for byte in bytes {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+
+    // ...followed by the splitmix64 finalizer, so that close epochs give unrelated seeds
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    hash ^ (hash >> 31)
+}
+
+// Returns unix epoch (in f64 seconds) - frequency pairs (in Hz)
+// This function has some fine-tuning parameters to match the timing of the actual transmitter!
+pub fn build_frequencies<'a>(
+    seqs: impl IntoIterator<Item = &'a Sequence>,
+    start_timestamp: i64,
+) -> Vec<(f64, f64)> {
+    build_frequencies_fref(seqs, start_timestamp, FREF_HZ)
+}
+
+/// Same as `build_frequencies`, for a reference measured to be at `fref_hz` instead of nominal
+pub fn build_frequencies_fref<'a>(
+    seqs: impl IntoIterator<Item = &'a Sequence>,
+    start_timestamp: i64,
+    fref_hz: f64,
+) -> Vec<(f64, f64)> {
+    const PLLCHANGE_S: f64 = PLLCHANGE_US * 1e-6;
+
+    let mut out = Vec::new();
+    let mut t = start_timestamp as f64;
+
+    for seq in seqs {
+        for change in seq.pllchange_buffer.iter() {
+            t += PLLCHANGE_S;
+            for i in 0..change.for_ticks {
+                let fracn = seq.fracn_buffer[change.start_tick + i];
+                let freq = pll_output_hz_fref(fref_hz, change.divn, change.divp, fracn);
+                out.push((t, freq));
+                t += change.tim_us as f64 * 1e-6;
+            }
+        }
+    }
+
+    out
+}
+
+/// Start times of the PLL changes of the sequences, during which the output is not locked for
+/// PLLCHANGE_US. Uses the same timeline as `build_frequencies`.
+pub fn build_pll_changes<'a>(
+    seqs: impl IntoIterator<Item = &'a Sequence>,
+    start_timestamp: i64,
+) -> Vec<f64> {
+    const PLLCHANGE_S: f64 = PLLCHANGE_US * 1e-6;
+
+    let mut out = Vec::new();
+    let mut t = start_timestamp as f64;
+
+    for seq in seqs {
+        for change in seq.pllchange_buffer.iter() {
+            out.push(t);
+            t += PLLCHANGE_S;
+            // Same accumulation as build_frequencies so times match exactly
+            for _ in 0..change.for_ticks {
+                t += change.tim_us as f64 * 1e-6;
+            }
+        }
+    }
+
+    out
+}
+
+/// Amplitude of the given harmonic of the square wave output, relative to the fundamental.
+/// An ideal square wave has no even harmonics.
+pub fn harmonic_amplitude(harmonic: usize) -> f64 {
+    if harmonic.is_multiple_of(2) {
+        0.0
+    } else {
+        1.0 / harmonic as f64
+    }
+}
+
+/// Converts a timeline of (time, frequency) of the fundamental, as returned by
+/// `build_frequencies`, into the (time, frequency, amplitude) timeline of the given harmonic
+pub fn harmonic_frequencies(freqs: &[(f64, f64)], harmonic: usize) -> Vec<(f64, f64, f64)> {
+    let amp = harmonic_amplitude(harmonic);
+
+    freqs
+        .iter()
+        .map(|&(t, freq)| (t, freq * harmonic as f64, amp))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seed_depends_on_rounded_epoch_index_and_secret() {
+        let seed = derive_seed(1_700_000_000, 3, Some(b"secret"));
+
+        assert_eq!(seed, derive_seed(1_700_000_009, 3, Some(b"secret")));
+        assert_ne!(seed, derive_seed(1_700_000_010, 3, Some(b"secret")));
+        assert_ne!(seed, derive_seed(1_700_000_000, 4, Some(b"secret")));
+        assert_ne!(seed, derive_seed(1_700_000_000, 3, Some(b"other")));
+        assert_ne!(seed, derive_seed(1_700_000_000, 3, None));
+    }
+
+    #[test]
+    fn split_patterns_stay_costas() {
+        let orders = crate::orders::parse_orders(String::from(
+            "type, duration, freq, bandwidth, hops, channels\n\
+             welch, 1s, 8MHz, 2MHz, 10, 10\n",
+        ))
+        .unwrap();
+        let order = &orders[0];
+
+        let subseqs = build_subsequences(order, 42).unwrap();
+        assert!(subseqs.len() > 1);
+
+        // Channel of every hop, in the order they are transmitted
+        let flow = order.freq_hz as f64 - order.bandwidth_hz as f64 / 2.0;
+        let width = order.bandwidth_hz as f64 / 10.0;
+        let channels: Vec<i64> = subseqs
+            .iter()
+            .flat_map(|s| &s.requested_hz)
+            .map(|f| ((f - flow) / width).floor() as i64)
+            .collect();
+
+        let mut vectors = Vec::new();
+        for i in 0..channels.len() {
+            for j in i + 1..channels.len() {
+                vectors.push((j - i, channels[j] - channels[i]));
+            }
+        }
+        let total = vectors.len();
+        vectors.sort();
+        vectors.dedup();
+        assert_eq!(vectors.len(), total);
+    }
+
+    #[test]
+    fn empty_bands_are_errors() {
+        assert!(split_band(7e6, 7e6).is_err());
+        assert!(split_band(8e6, 7e6).is_err());
+        assert!(split_band(0.0, 7e6).is_err());
+
+        let orders = crate::orders::parse_orders(String::from(
+            "type, duration, freq, bandwidth, hops\nrandom, 1s, 7MHz, 0Hz, 10\n",
+        ))
+        .unwrap();
+        assert!(build_subsequences(&orders[0], 0).is_err());
+    }
+
+    #[test]
+    fn unbuildable_orders_are_errors() {
+        let orders = crate::orders::parse_orders(String::from(
+            "type, duration, freq, bandwidth, hops\n\
+             random, 1s, 7MHz, 10kHz, 10\n\
+             random, 1s, 7MHz, 10kHz, 20000\n",
+        ))
+        .unwrap();
+
+        let err = build_sequences(&orders, 1_700_000_000, None).err().unwrap();
+        assert!(err.starts_with("Order 1:"), "{err}");
+
+        // Not enough time left for the hops once the PLLChanges are accounted for
+        let orders = crate::orders::parse_orders(String::from(
+            "type, duration, freq, bandwidth, hops\n\
+             random, 20us, 8MHz, 2MHz, 10\n",
+        ))
+        .unwrap();
+        assert!(build_sequences(&orders, 1_700_000_000, None).is_err());
+    }
+}
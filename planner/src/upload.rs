@@ -0,0 +1,192 @@
+//! Model of the time it takes to upload a sequence to the transmitter over the serial port,
+//! used to schedule uploads so every sequence is in the transmitter before it must start.
+
+use std::fmt;
+use std::ops::Range;
+
+use common::comm_messages::{DEFAULT_BAUD_RATE, MAX_UPLINK_MSG_SIZE, UplinkMsg};
+use common::sequence::Sequence;
+
+use crate::sequence::UploadPlan;
+
+/// Bits on the wire per byte, with one start and one stop bit
+const BITS_PER_BYTE: f64 = 10.0;
+/// Sequences are uploaded in frames of at most this many fracn
+const FRACN_PER_FRAME: usize = 32;
+/// Frames without a payload sent around each upload (ClearBuffer and UploadDone)
+const CONTROL_FRAMES: usize = 2;
+/// Encoded size of a frame without payload: COBS overhead, variant and terminator
+const CONTROL_FRAME_BYTES: usize = 3;
+/// Fraction of the measured ack latencies covered by the calibrated latency
+const CALIBRATION_PERCENTILE: f64 = 0.95;
+
+/// Upload time of a sequence, sent as one frame per message, each waiting for an ack
+pub struct UploadModel {
+    pub baud: u32,
+    /// Time from a frame being fully sent to its ack being received
+    pub ack_latency_us: f64,
+    /// Extra time given to every upload, to absorb scheduling jitter on the host
+    pub margin_us: f64,
+}
+
+impl Default for UploadModel {
+    fn default() -> Self {
+        UploadModel {
+            baud: DEFAULT_BAUD_RATE,
+            ack_latency_us: 2_000.0,
+            margin_us: 100_000.0,
+        }
+    }
+}
+
+/// Size of each frame needed to upload a sequence, once encoded
+pub fn frame_sizes(seq: &Sequence) -> Vec<usize> {
+    let mut databuf = [0u8; MAX_UPLINK_MSG_SIZE];
+    let mut encoded_len = |msg: &UplinkMsg| {
+        postcard::to_slice_cobs(msg, &mut databuf)
+            .expect("Uplink messages fit in MAX_UPLINK_MSG_SIZE")
+            .len()
+    };
+
+    let mut out = vec![CONTROL_FRAME_BYTES; CONTROL_FRAMES];
+
+    for slice in seq.fracn_buffer.chunks(FRACN_PER_FRAME) {
+        let mut fixedslice = [0u16; FRACN_PER_FRAME];
+        fixedslice[..slice.len()].copy_from_slice(slice);
+        out.push(encoded_len(&UplinkMsg::PushFracn(
+            slice.len() as u8,
+            fixedslice,
+        )));
+    }
+
+    for pll in &seq.pllchange_buffer {
+        out.push(encoded_len(&UplinkMsg::PushPLLChange(*pll)));
+    }
+
+    out
+}
+
+impl UploadModel {
+    /// Time to send a frame of `bytes` bytes over the wire, without waiting for the ack
+    pub fn wire_time_us(&self, bytes: usize) -> f64 {
+        bytes as f64 * BITS_PER_BYTE * 1e6 / self.baud as f64
+    }
+
+    /// Returns upload time estimate in us
+    pub fn estimate_upload_time(&self, seq: &Sequence) -> u64 {
+        let frames_us: f64 = frame_sizes(seq)
+            .into_iter()
+            .map(|bytes| self.wire_time_us(bytes) + self.ack_latency_us)
+            .sum();
+
+        (frames_us + self.margin_us).ceil() as u64
+    }
+
+    /// Sets the ack latency from the (frame bytes, send to ack us) measured on previous
+    /// uploads, so that most frames are acked within it
+    pub fn calibrate(&mut self, measurements: &[(usize, f64)]) {
+        if measurements.is_empty() {
+            return;
+        }
+
+        let mut latencies: Vec<f64> = measurements
+            .iter()
+            .map(|&(bytes, us)| (us - self.wire_time_us(bytes)).max(0.0))
+            .collect();
+        latencies.sort_by(f64::total_cmp);
+
+        let idx = ((latencies.len() - 1) as f64 * CALIBRATION_PERCENTILE).round() as usize;
+        self.ack_latency_us = latencies[idx];
+    }
+}
+
+/// A sequence of consecutive orders, and when it starts relative to the start of the plan
+pub struct TimedSequence {
+    pub toff_us: u64,
+    /// Indices of the orders in the sequence
+    pub orders: Range<usize>,
+    pub seq: Sequence,
+}
+
+/// A sequence that can't be uploaded before it has to start
+#[derive(Debug)]
+pub struct ScheduleError {
+    pub sequence: usize,
+    pub orders: Range<usize>,
+    pub frames: usize,
+    pub upload_us: u64,
+    pub available_us: i64,
+}
+
+impl fmt::Display for ScheduleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sequence {} (orders {} to {}) takes {:.3}s to upload in {} frames, but only \
+             {:.3}s are available while the previous sequence plays. Make the previous orders \
+             longer, split the sequence or use a faster baud rate",
+            self.sequence,
+            self.orders.start,
+            self.orders.end - 1,
+            self.upload_us as f64 * 1e-6,
+            self.frames,
+            self.available_us as f64 * 1e-6,
+        )
+    }
+}
+
+impl std::error::Error for ScheduleError {}
+
+/// Schedules the upload of each sequence so that it ends right before the sequence starts.
+/// The transmitter holds the sequence being played and the one being uploaded, so an upload
+/// may only start once the previous sequence is playing and the previous upload is done.
+pub fn schedule_uploads(
+    seqs: Vec<TimedSequence>,
+    model: &UploadModel,
+) -> Result<UploadPlan, ScheduleError> {
+    let mut out = UploadPlan::new();
+    // Earliest moment the next upload may start
+    let mut earliest_us = i64::MIN;
+
+    for (i, timed) in seqs.into_iter().enumerate() {
+        let upload_us = model.estimate_upload_time(&timed.seq);
+        let toff_us = timed.toff_us as i64;
+        let net_off_us = toff_us - upload_us as i64;
+
+        if net_off_us < earliest_us {
+            return Err(ScheduleError {
+                sequence: i,
+                orders: timed.orders,
+                frames: frame_sizes(&timed.seq).len(),
+                upload_us,
+                available_us: toff_us - earliest_us,
+            });
+        }
+
+        println!(
+            "Order with toff_us = {} landing at net_off_us = {}",
+            toff_us, net_off_us
+        );
+        out.insert(net_off_us, timed.seq);
+        earliest_us = toff_us;
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn calibration_covers_most_ack_latencies() {
+        let mut model = UploadModel::default();
+        let wire_us = model.wire_time_us(70);
+        let measurements: Vec<(usize, f64)> = (0..100)
+            .map(|i| (70, wire_us + 1_000.0 + i as f64 * 10.0))
+            .collect();
+        model.calibrate(&measurements);
+
+        assert!((model.ack_latency_us - 1_940.0).abs() < 1.0);
+    }
+}
@@ -5,7 +5,13 @@ use realfft::{ComplexToReal, RealFftPlanner, RealToComplex};
 use rustfft::{Fft, FftPlanner, num_complex::ComplexFloat};
 use sdriq::Complex;
 
-use crate::stream::{FreqChange, FreqOnTimes, Sample, Scalar, get_freqs_for_interval};
+use crate::stream::{
+    FreqChange, FreqOnTimes, Sample, Scalar, StreamedSamplesFreqs, get_freqs_around,
+    get_freqs_for_interval,
+};
+
+/// Most samples used in the time domain refinement of the delay
+const MAX_REFINE_SAMPLES: usize = 1 << 18;
 
 struct CorrelationBuffers {
     /// Accumulation buffer where the correlation results are summed
@@ -54,6 +60,14 @@ impl CorrelationBuffers {
     }
 }
 
+/// Result of correlating received samples against the reference
+pub struct Correlation {
+    /// Number of samples the received samples have to be delayed to match the reference
+    pub delay: f64,
+    /// Peak to sidelobe ratio of the accumulated correlation
+    pub psr: Scalar,
+}
+
 pub struct SpectrogramCorrelator {
     /// The number of samples on each spectrogram window
     window_size: usize,
@@ -268,7 +282,7 @@ impl SpectrogramCorrelator {
         azip!((a in &mut buffers.accum_corr, &b in &buffers.buff_rx) *a += b);
 
         let (max_idx, psr) = get_max_index_and_psr(&buffers.accum_corr);
-        log::info!("Max index = {} PSR = {}", max_idx, psr);
+        log::debug!("Max index = {} PSR = {}", max_idx, psr);
 
         // PSR weighted histogram
         let entry = buffers.max_index_histogram.entry(max_idx).or_insert(0);
@@ -279,7 +293,8 @@ impl SpectrogramCorrelator {
 
     /// Correlate the spectrogram build from the given `samples`, assumed to start at `t0`, and to be sampled
     /// at a rate of `samp_rate` samples per second, against the
-    /// reference frequencies `freqs`, returning the number of samples that `samples` has to be delayed
+    /// reference frequencies `freqs`, received `foffset` Hz above their nominal frequency,
+    /// returning the number of samples that `samples` has to be delayed
     /// (negative if it has to be advanced) to match the reference as good as possible
     pub fn correlate_against(
         &mut self,
@@ -287,13 +302,199 @@ impl SpectrogramCorrelator {
         t0: f64,
         samp_rate: u64,
         center_freq: f64,
+        foffset: f64,
+        freqs: &Vec<FreqChange>,
+    ) -> Correlation {
+        let num_windows = (samples.len() - self.window_size) / self.window_step + 1;
+        assert!(num_windows > 1);
+
+        let spectrogram = self.build_spectrogram(samples, num_windows);
+
+        let mut correlation =
+            self.correlate_spectrogram(&spectrogram, t0, samp_rate, center_freq - foffset, freqs);
+        correlation.delay = self.refine_delay(
+            samples,
+            t0,
+            samp_rate,
+            center_freq,
+            foffset,
+            freqs,
+            correlation.delay,
+        );
+
+        correlation
+    }
+
+    /// Like `correlate_against`, but with an unknown frequency offset, searched within
+    /// `max_foffset` Hz in steps of a spectrogram bin. Returns the offset giving the best PSR,
+    /// alongside its correlation.
+    pub fn search_against(
+        &mut self,
+        samples: &Array1<Sample>,
+        t0: f64,
+        samp_rate: u64,
+        center_freq: f64,
+        max_foffset: f64,
         freqs: &Vec<FreqChange>,
-    ) -> i64 {
+    ) -> (f64, Correlation) {
         let num_windows = (samples.len() - self.window_size) / self.window_step + 1;
         assert!(num_windows > 1);
 
         let spectrogram = self.build_spectrogram(samples, num_windows);
 
+        let hz_per_bin = samp_rate as f64 / self.window_size as f64;
+        let max_bins = (max_foffset / hz_per_bin).round() as i64;
+
+        let mut best: Option<(f64, Correlation)> = None;
+        for offset_bins in -max_bins..=max_bins {
+            let foffset = offset_bins as f64 * hz_per_bin;
+            let correlation = self.correlate_spectrogram(
+                &spectrogram,
+                t0,
+                samp_rate,
+                center_freq - foffset,
+                freqs,
+            );
+            log::info!(
+                "Offset {:.0}Hz: delay {:.1} samples, PSR {:.1}",
+                foffset,
+                correlation.delay,
+                correlation.psr
+            );
+
+            if best.as_ref().is_none_or(|(_, b)| correlation.psr > b.psr) {
+                best = Some((foffset, correlation));
+            }
+        }
+
+        let (foffset, mut correlation) = best.unwrap();
+        correlation.delay = self.refine_delay(
+            samples,
+            t0,
+            samp_rate,
+            center_freq,
+            foffset,
+            freqs,
+            correlation.delay,
+        );
+
+        (foffset, correlation)
+    }
+
+    /// Refines a `delay` found from the spectrograms, only known to a fraction of a window step,
+    /// by cross-correlating `samples` with the reference synthesized in the time domain, at every
+    /// lag within two windows of it. The correlation is summed in magnitude over windows, as the
+    /// phase of the received signal is only consistent over short spans. Its peak is only about
+    /// as wide as the sample rate over the hopped bandwidth, so no lag can be skipped, and each
+    /// window is correlated at all lags at once by FFT.
+    #[allow(clippy::too_many_arguments)]
+    fn refine_delay(
+        &self,
+        samples: &Array1<Sample>,
+        t0: f64,
+        samp_rate: u64,
+        center_freq: f64,
+        foffset: f64,
+        freqs: &[FreqChange],
+        delay: f64,
+    ) -> f64 {
+        let coarse = delay.round() as i64;
+        // The spectrogram delay is averaged over a long span, so may be off by more than its
+        // resolution if the delay drifts
+        let range = 2 * self.window_size as i64;
+        let len = samples.len() as i64;
+
+        // Reference samples must be compared against received ones at every lag searched
+        let to_t = |i: i64| t0 + i as f64 / samp_rate as f64;
+        let mut start = (range - coarse).max(0);
+        let end = len - (coarse + range).max(0);
+        let around = get_freqs_around(freqs, to_t(start), to_t(end));
+        if around.len() < 2 {
+            return delay;
+        }
+        let mut synth = StreamedSamplesFreqs::new(around, center_freq, samp_rate as u32)
+            .expect("Frequencies given");
+
+        // The reference is only synthesized from the first frequency on
+        let first = ((synth.get_first_epoch() - t0) * samp_rate as f64).ceil() as i64;
+        start = start.max(first);
+        let end = end.min(start + MAX_REFINE_SAMPLES as i64);
+        if end - start < self.window_size as i64 {
+            return delay;
+        }
+        let num_samples = (end - start) as usize;
+        synth.seek_epoch(to_t(start));
+        let (reference, nref) = synth.get_next(num_samples, foffset);
+
+        // Received samples of each window, at all lags, fit in one FFT without wrapping around
+        let num_lags = 2 * range as usize + 1;
+        let fft_len = (self.window_size + num_lags - 1).next_power_of_two();
+        let mut planner = FftPlanner::<Scalar>::new();
+        let fft = planner.plan_fft_forward(fft_len);
+        let ifft = planner.plan_fft_inverse(fft_len);
+        let scratch_len = fft
+            .get_inplace_scratch_len()
+            .max(ifft.get_inplace_scratch_len());
+        let mut scratch = vec![Sample::new(0.0, 0.0); scratch_len];
+        let mut buff_rx: Array1<Sample> = Array1::zeros(fft_len);
+        let mut buff_ref: Array1<Sample> = Array1::zeros(fft_len);
+
+        // Correlation at lags from `coarse - range` on
+        let mut accum: Array1<f64> = Array1::zeros(num_lags);
+        let first_lag = coarse - range;
+        let num_windows = nref / self.window_size;
+        for window in 0..num_windows {
+            let offset = window * self.window_size;
+            let from = (start + first_lag) as usize + offset;
+            let rx_len = self.window_size + num_lags - 1;
+
+            buff_rx.fill(Sample::new(0.0, 0.0));
+            buff_rx
+                .slice_mut(s![..rx_len])
+                .assign(&samples.slice(s![from..from + rx_len]));
+            buff_ref.fill(Sample::new(0.0, 0.0));
+            buff_ref
+                .slice_mut(s![..self.window_size])
+                .assign(&reference.slice(s![offset..offset + self.window_size]));
+
+            fft.process_with_scratch(buff_rx.as_slice_mut().unwrap(), &mut scratch);
+            fft.process_with_scratch(buff_ref.as_slice_mut().unwrap(), &mut scratch);
+            azip!((a in &mut buff_rx, &b in &buff_ref) *a *= b.conj());
+            ifft.process_with_scratch(buff_rx.as_slice_mut().unwrap(), &mut scratch);
+
+            azip!((a in &mut accum, &b in &buff_rx.slice(s![..num_lags])) *a += b.norm() as f64);
+        }
+
+        let (best, &peak) = accum
+            .iter()
+            .enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .unwrap();
+
+        // The correlation decreases linearly away from the delay, so interpolate a V shape
+        if best == 0 || best == num_lags - 1 {
+            return (first_lag + best as i64) as f64;
+        }
+        let (before, after) = (accum[best - 1], accum[best + 1]);
+        let fraction = (after - before) / (2.0 * (peak - before.min(after)));
+        let refined = (first_lag + best as i64) as f64 + fraction;
+
+        log::debug!("Delay refined from {:.1} to {:.2} samples", delay, refined);
+        refined
+    }
+
+    /// Correlate an already built `spectrogram` against the reference frequencies, seen as if
+    /// received at `center_freq`
+    fn correlate_spectrogram(
+        &mut self,
+        spectrogram: &Array2<Scalar>,
+        t0: f64,
+        samp_rate: u64,
+        center_freq: f64,
+        freqs: &Vec<FreqChange>,
+    ) -> Correlation {
+        let num_windows = spectrogram.ncols();
+
         let mut ref_spectrogram =
             self.build_ref_spectrogram(num_windows, t0, samp_rate, center_freq, freqs);
 
@@ -301,7 +502,7 @@ impl SpectrogramCorrelator {
 
         // Correlate lines with the most entries until a good result is achieved (good side-lobe ratio)
         while let Some((bin, line)) = ref_spectrogram.pull_biggest_line_ref() {
-            log::info!("Correlating bin {}", bin);
+            log::debug!("Correlating bin {}", bin);
 
             let rx_line = spectrogram.slice(s![bin, ..]);
             self.correlate_line(&rx_line, &line, &mut buffers);
@@ -312,14 +513,27 @@ impl SpectrogramCorrelator {
             }
         }
 
-        // Pick the most popular entry
+        // Pick the most popular entry, the lowest one if tied
         let max_entry = buffers
             .max_index_histogram
             .iter()
-            .max_by_key(|(_, v)| *v)
+            .max_by_key(|(k, v)| (**v, std::cmp::Reverse(**k)))
             .unwrap()
             .0;
-        log::info!("Max entry computed to be: {}", max_entry);
+        log::debug!("Max entry computed to be: {}", max_entry);
+
+        let psr = peak_to_sidelobe(&buffers.accum_corr, *max_entry);
+
+        // The peak is triangular, as wide as a hop, so interpolate it as a V shape
+        let len = buffers.accum_corr.len() as i64;
+        let at = |i: i64| buffers.accum_corr[i.rem_euclid(len) as usize];
+        let entry = *max_entry as i64;
+        let (before, peak, after) = (at(entry - 1), at(entry), at(entry + 1));
+        let fraction = if peak > before.min(after) {
+            ((after - before) / (2.0 * (peak - before.min(after)))).clamp(-0.5, 0.5)
+        } else {
+            0.0
+        };
 
         // TODO: Check that this is correct!
         let max_entry = if *max_entry as i64 > self.max_spectrogram_size as i64 {
@@ -329,7 +543,11 @@ impl SpectrogramCorrelator {
             *max_entry as i64
         };
 
-        max_entry * self.window_step as i64 + self.window_size as i64 / 2
+        Correlation {
+            delay: (max_entry as f64 + fraction as f64) * self.window_step as f64
+                + self.window_size as f64 / 2.0,
+            psr,
+        }
     }
 }
 
@@ -388,13 +606,17 @@ impl ReferenceSpectrogram {
     }
 
     // Searches the line with the most entries, gets it and removes it
-    // Returns the (bin, line) pair.
+    // Returns the (bin, line) pair. Ties go to the lowest bin, so the lines correlated don't
+    // depend on the order of the hash map.
     fn pull_biggest_line_ref(&mut self) -> Option<(usize, Array1<Scalar>)> {
         let mut max_entries = 0;
         let mut max_entries_line: Option<usize> = None;
 
         for line in &self.lines {
-            if line.1.num_entries > max_entries {
+            if line.1.num_entries > max_entries
+                || (line.1.num_entries == max_entries
+                    && max_entries_line.is_some_and(|bin| *line.0 < bin))
+            {
                 max_entries = line.1.num_entries;
                 max_entries_line = Some(*line.0);
             }
@@ -451,8 +673,9 @@ impl ReferenceSpectrogram {
         let start_samp = (start_rel_t * self.samp_rate) as i64;
         let end_samp = (end_rel_t * self.samp_rate) as i64;
 
-        self.add_entry_to_line(bins.0.0, start_samp, end_samp, bins.0.1);
-        self.add_entry_to_line(bins.1.0, start_samp, end_samp, bins.1.1);
+        let amp = freq.amp as Scalar;
+        self.add_entry_to_line(bins.0.0, start_samp, end_samp, bins.0.1 * amp);
+        self.add_entry_to_line(bins.1.0, start_samp, end_samp, bins.1.1 * amp);
     }
 
     // Given index of bin in spectrogram FFT, returns the center frequency of said bin
@@ -518,6 +741,31 @@ impl ReferenceSpectrogram {
     }
 }
 
+/// Ratio of the correlation at `peak` to the highest one out of its main lobe, both measured
+/// above the mean of the whole correlation (spectrogram magnitudes are never negative, so it
+/// has a large constant pedestal). The main lobe spans as far as the correlation keeps
+/// decreasing away from the peak, wrapping around as lags are circular.
+fn peak_to_sidelobe(data: &Array1<Scalar>, peak: usize) -> Scalar {
+    let n = data.len() as isize;
+    let at = |offset: isize| data[(peak as isize + offset).rem_euclid(n) as usize];
+
+    let mut before = 0;
+    while before + 1 < n / 2 && at(-before - 1) <= at(-before) {
+        before += 1;
+    }
+    let mut after = 0;
+    while after + 1 < n / 2 && at(after + 1) <= at(after) {
+        after += 1;
+    }
+
+    let mean = data.mean().unwrap_or(0.0);
+    let sidelobe = (after + 1..n - before)
+        .map(at)
+        .fold(Scalar::NEG_INFINITY, Scalar::max);
+
+    (data[peak] - mean) / (sidelobe - mean)
+}
+
 // Returns the index of the maximum value in data, and how big it's compared to
 // the next 10 biggest values (their average)
 fn get_max_index_and_psr(data: &Array1<Scalar>) -> (usize, Scalar) {
@@ -545,3 +793,106 @@ fn get_max_index_and_psr(data: &Array1<Scalar>) -> (usize, Scalar) {
     let avg: Scalar = max[1..].iter().sum::<Scalar>() / max[1..].len() as Scalar;
     (max_idx, max[0] / avg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::freqs_from_orders;
+
+    const SAMP_RATE: u64 = 100_003;
+    const CENTER_FREQ: f64 = 7e6;
+    const WINDOW_SIZE: usize = 512;
+    const WINDOW_STEP: usize = 256;
+    const EPOCH: i64 = 1_700_000_000;
+
+    /// Hops over 40kHz around the center, lasting 25ms each. The sample rate is not a multiple
+    /// of the hop rate, so hops don't all start at the same fraction of a sample.
+    fn hopping_freqs() -> Vec<FreqChange> {
+        let orders = "duration, freq, bandwidth, hops\n10s, 7MHz, 40kHz, 400";
+        freqs_from_orders(orders, EPOCH).0
+    }
+
+    /// Reference received `delay` samples late and `foffset` Hz high, from `t0` on
+    fn received(
+        freqs: &[FreqChange],
+        t0: f64,
+        delay: f64,
+        foffset: f64,
+        n: usize,
+    ) -> Array1<Sample> {
+        let mut synth =
+            StreamedSamplesFreqs::new(freqs.to_vec(), CENTER_FREQ, SAMP_RATE as u32).unwrap();
+        synth.seek_epoch(t0 - delay / SAMP_RATE as f64);
+        let (samples, num_read) = synth.get_next(n, foffset);
+        assert_eq!(num_read, n);
+        samples
+    }
+
+    #[test]
+    fn finds_delay_to_a_fraction_of_a_sample() {
+        let freqs = hopping_freqs();
+        let t0 = EPOCH as f64 + 2.0;
+
+        for delay in [123.3, -45.7, 1000.5] {
+            let mut correlator = SpectrogramCorrelator::new(WINDOW_SIZE, WINDOW_STEP, 700);
+            let n = correlator.get_max_length_samples();
+            let samples = received(&freqs, t0, delay, 0.0, n);
+
+            let correlation =
+                correlator.correlate_against(&samples, t0, SAMP_RATE, CENTER_FREQ, 0.0, &freqs);
+            assert!(
+                (correlation.delay - delay).abs() < 0.25,
+                "found {} for {}",
+                correlation.delay,
+                delay
+            );
+        }
+    }
+
+    #[test]
+    fn refines_a_coarse_delay() {
+        let freqs = hopping_freqs();
+        let t0 = EPOCH as f64 + 2.0;
+        let correlator = SpectrogramCorrelator::new(WINDOW_SIZE, WINDOW_STEP, 700);
+        let n = correlator.get_max_length_samples();
+
+        for delay in [321.3, 321.7] {
+            let samples = received(&freqs, t0, delay, 0.0, n);
+            // From anywhere within the span searched around the spectrogram delay
+            for coarse in [delay - 200.0, delay, delay + 200.0] {
+                let refined = correlator.refine_delay(
+                    &samples,
+                    t0,
+                    SAMP_RATE,
+                    CENTER_FREQ,
+                    0.0,
+                    &freqs,
+                    coarse,
+                );
+                assert!(
+                    (refined - delay).abs() < 0.25,
+                    "refined {} from {}",
+                    refined,
+                    coarse
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn searches_the_frequency_offset() {
+        let freqs = hopping_freqs();
+        let t0 = EPOCH as f64 + 2.0;
+        let hz_per_bin = SAMP_RATE as f64 / WINDOW_SIZE as f64;
+        let foffset = -3.0 * hz_per_bin;
+        let delay = 80.0;
+        let mut correlator = SpectrogramCorrelator::new(WINDOW_SIZE, WINDOW_STEP, 700);
+        let n = correlator.get_max_length_samples();
+        let samples = received(&freqs, t0, delay, foffset, n);
+
+        let (found, correlation) =
+            correlator.search_against(&samples, t0, SAMP_RATE, CENTER_FREQ, 1000.0, &freqs);
+        assert_eq!(found, foffset);
+        assert!((correlation.delay - delay).abs() < 0.25);
+    }
+}
@@ -0,0 +1,263 @@
+//! Anti-aliased decimation of the mixed output. The decimation is split into stages, one per
+//! prime factor, each a lowpass FIR filter (a Kaiser windowed sinc) evaluated only at the
+//! samples it keeps. Early stages only have to keep what would alias into the passband, so
+//! their filters are short, and the last one sets the final stopband.
+
+use anyhow::{Result, anyhow};
+use ndarray::prelude::*;
+
+use crate::stream::{Sample, Scalar};
+
+/// Attenuation of every stage in its stopband, in dB
+const STOPBAND_DB: f64 = 80.0;
+
+/// Modified Bessel function of the first kind and order zero, by its power series
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)).powi(2);
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+/// Lowpass taps for a passband up to `passband` and a stopband from `stopband`, both relative
+/// to the sample rate, with unit gain at DC. Fails unless 0 < passband < stopband <= 0.5.
+fn design_lowpass(passband: f64, stopband: f64) -> Result<Vec<Scalar>> {
+    if !(passband > 0.0 && passband < stopband && stopband <= 0.5) {
+        return Err(anyhow!(
+            "Lowpass needs 0 < passband ({}) < stopband ({}) <= half the sample rate",
+            passband,
+            stopband
+        ));
+    }
+
+    let transition = 2.0 * std::f64::consts::PI * (stopband - passband);
+    let num_taps = ((STOPBAND_DB - 8.0) / (2.285 * transition)).ceil() as usize | 1;
+    let beta = 0.1102 * (STOPBAND_DB - 8.7);
+    let cutoff = (passband + stopband) / 2.0;
+
+    let center = (num_taps / 2) as f64;
+    let taps: Vec<f64> = (0..num_taps)
+        .map(|i| {
+            let x = i as f64 - center;
+            let sinc = if x == 0.0 {
+                2.0 * cutoff
+            } else {
+                (2.0 * std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * x)
+            };
+            let r = x / center;
+            let window = bessel_i0(beta * (1.0 - r * r).max(0.0).sqrt()) / bessel_i0(beta);
+            sinc * window
+        })
+        .collect();
+
+    let gain: f64 = taps.iter().sum();
+    Ok(taps.iter().map(|t| (t / gain) as Scalar).collect())
+}
+
+/// A single decimating filter, keeping the input it still needs across calls
+struct Stage {
+    taps: Vec<Scalar>,
+    factor: usize,
+    /// Last `taps.len() - 1` input samples
+    history: Vec<Sample>,
+    /// Index in the next input of the next sample kept
+    phase: usize,
+}
+
+impl Stage {
+    fn new(taps: Vec<Scalar>, factor: usize) -> Self {
+        let history = vec![Sample::new(0.0, 0.0); taps.len() - 1];
+        Self {
+            taps,
+            factor,
+            history,
+            phase: 0,
+        }
+    }
+
+    fn process(&mut self, input: &[Sample]) -> Vec<Sample> {
+        let hlen = self.history.len();
+        let buffer: Vec<Sample> = self.history.iter().chain(input).copied().collect();
+
+        let mut out = Vec::with_capacity(input.len() / self.factor + 1);
+        let mut i = self.phase;
+        while i < input.len() {
+            // Taps are symmetric, so the convolution can run forward over the buffer
+            let window = &buffer[i..i + hlen + 1];
+            let acc = window
+                .iter()
+                .zip(&self.taps)
+                .fold(Sample::new(0.0, 0.0), |acc, (&x, &t)| acc + x * t);
+            out.push(acc);
+            i += self.factor;
+        }
+
+        self.phase = i - input.len();
+        self.history.copy_from_slice(&buffer[buffer.len() - hlen..]);
+
+        out
+    }
+}
+
+pub struct Decimator {
+    stages: Vec<Stage>,
+}
+
+impl Decimator {
+    /// Decimator by `factor`, keeping frequencies up to `passband` Hz and attenuating those
+    /// from `stopband` Hz on, for an input sampled at `samp_rate`. Fails if the bands can't be
+    /// kept at the decimated rate.
+    pub fn new(factor: usize, samp_rate: f64, passband: f64, stopband: f64) -> Result<Self> {
+        if factor == 0 {
+            return Err(anyhow!("Decimation factor must be at least 1"));
+        }
+        if factor > 1 && stopband > samp_rate / factor as f64 / 2.0 {
+            return Err(anyhow!(
+                "Stopband of {}Hz is above the {}Hz Nyquist frequency of the decimated output",
+                stopband,
+                samp_rate / factor as f64 / 2.0
+            ));
+        }
+
+        let mut factors = Vec::new();
+        let mut remain = factor;
+        let mut p = 2;
+        while remain > 1 {
+            while remain.is_multiple_of(p) {
+                factors.push(p);
+                remain /= p;
+            }
+            p += 1;
+        }
+        // Largest factors first, as they run at the highest rate with the widest transition
+        factors.reverse();
+
+        let mut stages = Vec::with_capacity(factors.len());
+        let mut rate = samp_rate;
+        for (i, &stage_factor) in factors.iter().enumerate() {
+            let out_rate = rate / stage_factor as f64;
+            // Intermediate stages only need to stop what would alias into the passband
+            let stage_stopband = if i + 1 == factors.len() {
+                stopband
+            } else {
+                out_rate - passband
+            };
+            let taps = design_lowpass(passband / rate, stage_stopband / rate).map_err(|e| {
+                anyhow!(
+                    "Cannot decimate by {} with a {}Hz passband and {}Hz stopband, {}",
+                    factor,
+                    passband,
+                    stopband,
+                    e
+                )
+            })?;
+            log::info!(
+                "Decimation stage by {} with {} taps",
+                stage_factor,
+                taps.len()
+            );
+            stages.push(Stage::new(taps, stage_factor));
+            rate = out_rate;
+        }
+
+        Ok(Self { stages })
+    }
+
+    /// Decimates the next `input` samples, continuing from the previous call
+    pub fn process(&mut self, input: ArrayView1<Sample>) -> Array1<Sample> {
+        let mut samples = input.to_vec();
+        for stage in &mut self.stages {
+            samples = stage.process(&samples);
+        }
+        Array1::from(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Magnitude of the response of `taps` at `freq`, relative to the sample rate
+    fn response(taps: &[Scalar], freq: f64) -> f64 {
+        taps.iter()
+            .enumerate()
+            .map(|(i, &t)| {
+                rustfft::num_complex::Complex::from_polar(
+                    t as f64,
+                    -std::f64::consts::TAU * freq * i as f64,
+                )
+            })
+            .sum::<rustfft::num_complex::Complex<f64>>()
+            .norm()
+    }
+
+    #[test]
+    fn lowpass_keeps_passband_and_stops_stopband() {
+        let taps = design_lowpass(0.1, 0.15).unwrap();
+        assert_eq!(taps.len() % 2, 1);
+        assert!((response(&taps, 0.0) - 1.0).abs() < 1e-5);
+
+        for i in 0..=100 {
+            let pass = 0.1 * i as f64 / 100.0;
+            assert!(
+                (response(&taps, pass) - 1.0).abs() < 1e-3,
+                "passband {pass}"
+            );
+
+            let stop = 0.15 + 0.35 * i as f64 / 100.0;
+            let db = 20.0 * response(&taps, stop).log10();
+            assert!(db < -STOPBAND_DB + 3.0, "stopband {stop} at {db}dB");
+        }
+    }
+
+    #[test]
+    fn lowpass_rejects_invalid_bands() {
+        assert!(design_lowpass(0.0, 0.2).is_err());
+        assert!(design_lowpass(0.2, 0.2).is_err());
+        assert!(design_lowpass(0.3, 0.2).is_err());
+        assert!(design_lowpass(0.2, 0.6).is_err());
+    }
+
+    #[test]
+    fn chunked_output_matches_single_call() {
+        let input: Array1<Sample> = (0..20_000)
+            .map(|i| {
+                let i = i as f64;
+                Sample::new((0.01 * i).sin() as Scalar, (0.37 * i).cos() as Scalar)
+            })
+            .collect();
+        let new = || Decimator::new(12, 48000.0, 1500.0, 2000.0).unwrap();
+
+        let expected = new().process(input.view());
+        assert_eq!(expected.len(), input.len().div_ceil(12));
+
+        let mut chunked = new();
+        let mut out = Vec::new();
+        let mut from = 0;
+        for len in [1, 5, 1000, 7, 4096].into_iter().cycle() {
+            if from == input.len() {
+                break;
+            }
+            let to = (from + len).min(input.len());
+            out.extend(chunked.process(input.slice(s![from..to])));
+            from = to;
+        }
+
+        assert_eq!(out.len(), expected.len());
+        for (a, b) in out.iter().zip(&expected) {
+            assert!((a - b).norm() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn decimation_needs_room_for_the_stopband() {
+        assert!(Decimator::new(0, 48000.0, 1500.0, 2000.0).is_err());
+        assert!(Decimator::new(12, 48000.0, 1500.0, 2500.0).is_err());
+        assert!(Decimator::new(1, 48000.0, 1500.0, 2500.0).is_ok());
+    }
+}
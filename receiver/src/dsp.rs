@@ -1,8 +1,22 @@
 use crate::correlator::SpectrogramCorrelator;
+use crate::decimate::Decimator;
+use crate::resample::FarrowResampler;
 use crate::stream::{Sample, Scalar, StreamedSamplesFreqs};
 use anyhow::Result;
-use ndarray::{Array1, azip};
+use ndarray::{Array1, ArrayView1, azip, s};
 use std::fs::File;
+use std::io::{Seek, Write};
+
+/// Number of baseband samples processed at once
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Minimum PSR (peak-to-sidelobe ratio) for a correlation to be trusted, unless given. Wrong lags
+/// stay around 2, while correct ones at 10dB SNR range from about 14 to 30.
+pub const DEFAULT_MIN_PSR: Scalar = 8.0;
+
+/// Minimum coherence of the phase changes of the mixed samples to trust the residual
+/// frequency offset measured from them
+const MIN_COHERENCE: f64 = 0.25;
 
 pub struct DspSettings {
     // Window size in samples.
@@ -16,10 +30,32 @@ pub struct DspSettings {
     // How many windows to use during the adjust phase
     pub spectrogram_size_adjust: usize,
 
+    // Time between correlations of the tracking loop, in seconds of baseband
+    pub track_interval_s: f64,
+
     // Decimation for the output "mixed" signal
     pub output_decimate: usize,
+    // Highest frequency kept by the decimation, and lowest one fully attenuated, in Hz
+    pub output_passband: f64,
+    pub output_stopband: f64,
     // Minimum PSR (peak-to-sidelobe ratio) for a correlation to be considered successful
     pub min_psr: Scalar,
+
+    // Frequency offset of the received signal, in Hz, estimated if not given
+    pub foffset: Option<f64>,
+    // Largest frequency offset searched while estimating it, in Hz
+    pub max_foffset: f64,
+}
+
+/// Delay of the baseband with respect to the reference, as measured by the tracking loop
+pub struct TrackPoint {
+    /// Epoch of the reference at the start of the correlated samples
+    pub t: f64,
+    /// Total delay applied to the baseband up to this point, including the drift of the
+    /// resampling, in samples
+    pub delay: f64,
+    /// Peak to sidelobe ratio of the correlation
+    pub psr: Scalar,
 }
 
 pub struct Dsp {
@@ -27,8 +63,25 @@ pub struct Dsp {
     freqs: StreamedSamplesFreqs,
     settings: DspSettings,
     correlator: SpectrogramCorrelator,
+    /// Shorter correlator, used to track the delay once the signal has been found
+    adjust_correlator: SpectrogramCorrelator,
 
     first_run: bool,
+    /// Set once the baseband or the reference run out of samples
+    done: bool,
+    /// Filters and decimates the mixed output
+    decimator: Decimator,
+    /// Baseband samples processed since the last correlation
+    since_track: usize,
+    /// Total delay applied to the baseband by seeking, in samples
+    delay: i64,
+    track: Vec<TrackPoint>,
+    /// Resamples the baseband to the rate of the reference
+    resampler: FarrowResampler,
+    /// Sample rate of the baseband relative to the reference, minus one, in parts per million
+    ppm: Option<f64>,
+    /// Frequency offset applied to the reference, in Hz
+    foffset: f64,
 }
 
 impl Dsp {
@@ -36,20 +89,42 @@ impl Dsp {
         baseband: sdriq::Source<File>,
         freqs: StreamedSamplesFreqs,
         settings: DspSettings,
-    ) -> Self {
+    ) -> Result<Self> {
         let correlator = SpectrogramCorrelator::new(
             settings.window_size,
             settings.window_step,
             settings.spectrogram_size_search,
         );
+        let adjust_correlator = SpectrogramCorrelator::new(
+            settings.window_size,
+            settings.window_step,
+            settings.spectrogram_size_adjust,
+        );
+
+        let foffset = settings.foffset.unwrap_or(0.0);
+        let decimator = Decimator::new(
+            settings.output_decimate,
+            baseband.get_header().samp_rate as f64,
+            settings.output_passband,
+            settings.output_stopband,
+        )?;
 
-        Self {
+        Ok(Self {
             baseband,
             freqs,
             settings,
             correlator,
+            adjust_correlator,
             first_run: true,
-        }
+            done: false,
+            decimator,
+            since_track: 0,
+            delay: 0,
+            track: Vec::new(),
+            resampler: FarrowResampler::new(),
+            ppm: None,
+            foffset,
+        })
     }
 
     pub fn first_run(&mut self) -> Result<()> {
@@ -79,19 +154,44 @@ impl Dsp {
         let samp_rate = self.baseband.get_header().samp_rate as u64;
         let center_freq = self.baseband.get_header().center_freq as f64;
 
-        let delay_in_samples = self.correlator.correlate_against(
-            &buffer,
-            start,
-            samp_rate,
-            center_freq,
-            self.freqs.get_freqs(),
-        );
+        let correlation = match self.settings.foffset {
+            Some(foffset) => self.correlator.correlate_against(
+                &buffer,
+                start,
+                samp_rate,
+                center_freq,
+                foffset,
+                self.freqs.get_freqs(),
+            ),
+            None => {
+                let (foffset, correlation) = self.correlator.search_against(
+                    &buffer,
+                    start,
+                    samp_rate,
+                    center_freq,
+                    self.settings.max_foffset,
+                    self.freqs.get_freqs(),
+                );
+                log::info!("Frequency offset found to be {:.0}Hz", foffset);
+                self.foffset = foffset;
+                correlation
+            }
+        };
+        let delay_in_samples = correlation.delay.round() as i64;
 
-        let delay_in_time = delay_in_samples as f64 / self.baseband.get_header().samp_rate as f64;
+        if correlation.psr < self.settings.min_psr {
+            log::warn!(
+                "Correlation PSR {:.1} below {:.1}, the delay may be wrong",
+                correlation.psr,
+                self.settings.min_psr
+            );
+        }
+
+        let delay_in_time = correlation.delay / self.baseband.get_header().samp_rate as f64;
 
         log::info!(
-            "Delay  in samples = {}, in time = {}ms",
-            delay_in_samples,
+            "Delay  in samples = {:.2}, in time = {}ms",
+            correlation.delay,
             delay_in_time * 1000.0,
         );
 
@@ -103,35 +203,407 @@ impl Dsp {
             .seek(std::io::SeekFrom::Current(delay_in_samples))?;
 
         // Now baseband is more or less exactly in line with reference samples
+        self.delay = delay_in_samples;
+        self.track.push(TrackPoint {
+            t: start0,
+            delay: correlation.delay,
+            psr: correlation.psr,
+        });
 
         self.first_run = false;
 
         Ok(())
     }
 
+    /// Processes up to `samples` samples, returning the decimated output. Fewer samples are
+    /// returned once the baseband or the reference run out, and none after that.
     pub fn run(&mut self, samples: usize) -> Result<Array1<Sample>> {
         if self.first_run {
             self.first_run()?;
         }
+        if self.done {
+            return Ok(Array1::zeros(0));
+        }
+
+        // Get samples from both rx baseband (resampled to the reference rate) and reference
+        // (with an offset) and mix them together
+        let mut raw_samples: Array1<Sample> = Array1::zeros(samples);
+        let nread = self
+            .baseband
+            .get_samples_norm(raw_samples.as_slice_mut().unwrap())?;
+        let mut rx_samples = self.resampler.process(raw_samples.slice(s![..nread]));
 
-        let mut out = Array1::zeros(samples / self.settings.output_decimate);
+        let (ref_samples, nref) = self.freqs.get_next(rx_samples.len(), self.foffset);
+        let n = rx_samples.len().min(nref);
+        self.done = nread < samples || nref < rx_samples.len();
+        self.since_track += nread;
+
+        // Modify rx_samples so it contains the mixed result
+        azip!((a in &mut rx_samples.slice_mut(s![..n]), &b in &ref_samples.slice(s![..n])) *a *= b.conj());
 
-        // Get samples from both rx baseband and reference (with an offset) and mix them together
-        let mut rx_samples: Array1<Sample> = Array1::zeros(samples);
+        if self.settings.foffset.is_none() {
+            self.refine_foffset(rx_samples.slice(s![..n]));
+        }
+
+        Ok(self.decimator.process(rx_samples.slice(s![..n])))
+    }
+
+    /// Correlates the upcoming baseband samples against the reference again, and moves the
+    /// baseband by the residual delay so it stays aligned even if the clocks drift
+    fn track(&mut self) -> Result<()> {
+        self.since_track = 0;
+
+        let nsamples = self.adjust_correlator.get_max_length_samples();
+        let mut buffer = Array1::zeros(nsamples);
+        let nread = self
+            .baseband
+            .get_samples_norm(buffer.as_slice_mut().unwrap())?;
         self.baseband
-            .get_samples_norm(rx_samples.as_slice_mut().unwrap())?;
+            .seek(std::io::SeekFrom::Current(-(nread as i64)))?;
 
-        let ref_samples = self.freqs.get_next(samples, 10000.0).0;
+        if nread < nsamples {
+            log::info!("Not enough baseband left to track the delay");
+            return Ok(());
+        }
 
-        // Modify rx_samples so it contains the mixed result
-        azip!((a in &mut rx_samples, &b in &ref_samples) *a *= b.conj());
+        let t = self.freqs.get_epoch();
+        let samp_rate = self.baseband.get_header().samp_rate as u64;
+        let center_freq = self.baseband.get_header().center_freq as f64;
+        let correlation = self.adjust_correlator.correlate_against(
+            &buffer,
+            t,
+            samp_rate,
+            center_freq,
+            self.foffset,
+            self.freqs.get_freqs(),
+        );
+
+        if correlation.psr < self.settings.min_psr {
+            log::info!(
+                "Tracking at {:.3}: PSR {:.1} too low, keeping delay",
+                t,
+                correlation.psr
+            );
+            return Ok(());
+        }
+
+        // The correlated samples start at the next input of the resampler, not at the next sample
+        // it outputs. Only whole samples can be skipped, the fraction left is still tracked.
+        let exact_residual = correlation.delay - self.resampler.get_position();
+        let residual = exact_residual.round() as i64;
+        self.baseband.seek(std::io::SeekFrom::Current(residual))?;
+        self.delay += residual;
+        let delay =
+            self.delay as f64 + self.resampler.get_drift() + exact_residual - residual as f64;
+        log::info!(
+            "Tracking at {:.3}: residual {:.2} samples, delay {:.2} samples = {:.4}ms, PSR {:.1}",
+            t,
+            exact_residual,
+            delay,
+            delay / samp_rate as f64 * 1000.0,
+            correlation.psr
+        );
+        self.track.push(TrackPoint {
+            t,
+            delay,
+            psr: correlation.psr,
+        });
+
+        self.estimate_ppm(samp_rate as f64);
+
+        Ok(())
+    }
+
+    /// Estimates the sample rate offset from the slope of the delays found so far, fitted by
+    /// least squares, and resamples the baseband to compensate it
+    fn estimate_ppm(&mut self, samp_rate: f64) {
+        /// Shortest span of delays to fit, as the delays are only known to a window step
+        const MIN_SPAN_S: f64 = 5.0;
+
+        let (first, last) = (&self.track[0], &self.track[self.track.len() - 1]);
+        if last.t - first.t < MIN_SPAN_S {
+            return;
+        }
 
-        // TODO: Perform some kind of interpolation?
-        for i in (0..samples).step_by(self.settings.output_decimate) {
-            out[i / self.settings.output_decimate] = rx_samples[i];
-            //out[i / self.settings.output_decimate] = ref_samples[i];
+        let num = self.track.len() as f64;
+        let mean_t = self.track.iter().map(|p| p.t).sum::<f64>() / num;
+        let mean_delay = self.track.iter().map(|p| p.delay).sum::<f64>() / num;
+        let (cov, var) = self.track.iter().fold((0.0, 0.0), |(cov, var), p| {
+            let dt = p.t - mean_t;
+            (cov + dt * (p.delay - mean_delay), var + dt * dt)
+        });
+
+        // The delay grows by this many samples per second of reference
+        let slope = cov / var;
+        let ppm = slope / samp_rate * 1e6;
+        log::info!("Baseband sample rate offset estimated to be {:.2}ppm", ppm);
+
+        self.resampler.set_ratio(1.0 + slope / samp_rate);
+        self.ppm = Some(ppm);
+    }
+
+    /// Corrects the frequency offset by the rotation left in the `mixed` samples, measured
+    /// from their mean phase change over a few samples
+    fn refine_foffset(&mut self, mixed: ArrayView1<Sample>) {
+        // Residual offsets of up to 4 spectrogram bins can be measured unambiguously, more than
+        // the half bin left by the search
+        let lag = self.settings.window_size / 8;
+        if mixed.len() <= lag {
+            return;
+        }
+
+        let early = mixed.slice(s![..mixed.len() - lag]);
+        let late = mixed.slice(s![lag..]);
+        let mut rotation = Sample::new(0.0, 0.0);
+        let mut power: Scalar = 0.0;
+        azip!((&a in &early, &b in &late) {
+            rotation += b * a.conj();
+            power += a.norm() * b.norm();
+        });
+
+        // Phase is randomized at PLL changes and by noise, so only trust a consistent rotation
+        if power <= 0.0 {
+            return;
+        }
+        let coherence = (rotation.norm() / power) as f64;
+        if coherence < MIN_COHERENCE {
+            return;
+        }
+
+        let samp_rate = self.baseband.get_header().samp_rate as f64;
+        let residual =
+            rotation.arg() as f64 / (2.0 * std::f64::consts::PI * lag as f64) * samp_rate;
+        self.foffset += residual;
+        log::info!(
+            "Frequency offset refined by {:.1}Hz to {:.1}Hz (coherence {:.2})",
+            residual,
+            self.foffset,
+            coherence
+        );
+    }
+
+    /// Frequency offset of the received signal, in Hz, as given or estimated so far
+    pub fn get_foffset(&self) -> f64 {
+        self.foffset
+    }
+
+    /// Sample rate of the baseband relative to the reference, minus one, in parts per million,
+    /// once enough of the recording has been tracked to estimate it
+    pub fn get_ppm(&self) -> Option<f64> {
+        self.ppm
+    }
+
+    /// Delay of the baseband over time, as found by the first correlation and the tracking loop
+    pub fn get_track(&self) -> &[TrackPoint] {
+        &self.track
+    }
+
+    /// Processes the whole recording, in chunks, writing the output to `sink` as it's produced.
+    /// Returns the number of output samples written.
+    pub fn run_to_end<W: Write + Seek>(&mut self, sink: &mut sdriq::Sink<W>) -> Result<usize> {
+        let tstep =
+            self.settings.output_decimate as f64 / self.baseband.get_header().samp_rate as f64;
+        let mut num_written = 0;
+
+        let track_samples =
+            (self.settings.track_interval_s * self.baseband.get_header().samp_rate as f64) as usize;
+
+        while !self.done {
+            if !self.first_run && track_samples > 0 && self.since_track >= track_samples {
+                self.track()?;
+            }
+            let out = self.run(CHUNK_SIZE)?;
+            sink.write_all_samples_denorm(out.as_slice().expect("Flat memory"))?;
+            num_written += out.len();
+            log::info!("Processed {:.1}s", num_written as f64 * tstep);
         }
 
-        Ok(out)
+        Ok(num_written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::correlator::SpectrogramCorrelator;
+    use crate::simulate::{SimulationSettings, Tap, simulate};
+    use crate::stream::freqs_from_orders;
+
+    const SAMP_RATE: u32 = 100_000;
+    const CENTER_FREQ: f64 = 7e6;
+    const EPOCH: i64 = 1_700_000_000;
+    const ORDERS: &str = "duration, freq, bandwidth, hops\n20s, 7MHz, 40kHz, 800";
+
+    fn simulation(delay_s: f64, clock_ppm: f64, foffset: f64) -> SimulationSettings {
+        SimulationSettings {
+            center_freq: CENTER_FREQ,
+            samp_rate: SAMP_RATE,
+            delay_s,
+            clock_ppm,
+            foffset,
+            taps: vec![Tap {
+                delay_s: 0.0,
+                gain: Sample::new(1.0, 0.0),
+            }],
+            snr_db: Some(10.0),
+            transient_us: planner::sequence::PLLCHANGE_US,
+            level: 0.25,
+            seed: 1,
+        }
+    }
+
+    fn dsp_settings(foffset: Option<f64>) -> DspSettings {
+        DspSettings {
+            window_size: 512,
+            window_step: 256,
+            spectrogram_size_search: 2000,
+            spectrogram_size_adjust: 1000,
+            track_interval_s: 2.0,
+            output_decimate: 1,
+            output_passband: 40000.0,
+            output_stopband: 50000.0,
+            min_psr: DEFAULT_MIN_PSR,
+            foffset,
+            max_foffset: 1000.0,
+        }
+    }
+
+    /// Runs the receiver over the baseband simulated with `simulation`
+    fn run_simulated(name: &str, simulation: &SimulationSettings, settings: DspSettings) -> Dsp {
+        let (freqs, pll_changes) = freqs_from_orders(ORDERS, EPOCH);
+        let path =
+            std::env::temp_dir().join(format!("direct-rf-{}-{}.sdriq", name, std::process::id()));
+        simulate(
+            freqs.clone(),
+            pll_changes,
+            simulation,
+            path.to_string_lossy().into_owned(),
+        )
+        .unwrap();
+
+        let baseband = sdriq::Source::new(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let reference = StreamedSamplesFreqs::new(freqs, CENTER_FREQ, SAMP_RATE).unwrap();
+        let mut dsp = Dsp::new(baseband, reference, settings).unwrap();
+
+        let header = sdriq::Header {
+            samp_rate: SAMP_RATE,
+            center_freq: 0,
+            start_timestamp: 0,
+            samp_size: 24,
+        };
+        let mut sink = sdriq::Sink::new(std::io::Cursor::new(Vec::new()), header).unwrap();
+        dsp.run_to_end(&mut sink).unwrap();
+        dsp
+    }
+
+    #[test]
+    fn recovers_simulated_delay_ppm_and_foffset() {
+        let (delay_s, clock_ppm, foffset) = (0.0123, 10.0, 450.0);
+        let dsp = run_simulated(
+            "recovers",
+            &simulation(delay_s, clock_ppm, foffset),
+            dsp_settings(None),
+        );
+
+        // The transmitter clock running fast shortens the received sequence, so the delay
+        // shrinks over time. Each delay is averaged over the span correlated, so it's only
+        // expected within a couple of samples.
+        let track = dsp.get_track();
+        assert!(track.len() >= 2);
+        for point in track {
+            let elapsed = point.t - EPOCH as f64;
+            let expected = (delay_s - elapsed * clock_ppm * 1e-6) * SAMP_RATE as f64;
+            assert!(
+                (point.delay - expected).abs() < 2.0,
+                "delay {} at {}s, expected {}",
+                point.delay,
+                elapsed,
+                expected
+            );
+        }
+
+        let ppm = dsp.get_ppm().unwrap();
+        assert!((ppm + clock_ppm).abs() < 0.5, "ppm {}", ppm);
+
+        // The clock error also shifts the carrier
+        let expected_foffset = foffset + CENTER_FREQ * clock_ppm * 1e-6;
+        assert!(
+            (dsp.get_foffset() - expected_foffset).abs() < 2.0,
+            "foffset {}, expected {}",
+            dsp.get_foffset(),
+            expected_foffset
+        );
+    }
+
+    #[test]
+    fn keeps_the_given_foffset() {
+        let (delay_s, foffset) = (0.0021, -300.0);
+        let dsp = run_simulated(
+            "given-foffset",
+            &simulation(delay_s, 0.0, foffset),
+            dsp_settings(Some(foffset)),
+        );
+
+        assert_eq!(dsp.get_foffset(), foffset);
+        for point in dsp.get_track() {
+            assert!((point.delay - delay_s * SAMP_RATE as f64).abs() < 0.5);
+        }
+    }
+
+    #[test]
+    fn default_min_psr_separates_right_and_wrong_lags() {
+        let (freqs, pll_changes) = freqs_from_orders(ORDERS, EPOCH);
+        // Same band and hop rate, but hopping in another order
+        let (other, _) = freqs_from_orders(
+            "duration, freq, bandwidth, hops, seed\n20s, 7MHz, 40kHz, 800, 12345",
+            EPOCH,
+        );
+        let path = std::env::temp_dir().join(format!("direct-rf-psr-{}.sdriq", std::process::id()));
+        simulate(
+            freqs.clone(),
+            pll_changes,
+            &simulation(0.0123, 0.0, 0.0),
+            path.to_string_lossy().into_owned(),
+        )
+        .unwrap();
+        let mut baseband = sdriq::Source::new(File::open(&path).unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let mut correlator = SpectrogramCorrelator::new(512, 256, 2000);
+        for elapsed in [0, 4, 8, 12] {
+            let t = (EPOCH + elapsed) as f64;
+            baseband.seek_to_timestamp((t * 1000.0) as u64).unwrap();
+            let mut buffer = Array1::zeros(correlator.get_max_length_samples());
+            baseband
+                .get_samples_norm(buffer.as_slice_mut().unwrap())
+                .unwrap();
+
+            let right = correlator.correlate_against(
+                &buffer,
+                t,
+                SAMP_RATE as u64,
+                CENTER_FREQ,
+                0.0,
+                &freqs,
+            );
+            assert!(
+                (12.0..35.0).contains(&right.psr),
+                "PSR {} at {}s",
+                right.psr,
+                elapsed
+            );
+
+            let wrong = correlator.correlate_against(
+                &buffer,
+                t,
+                SAMP_RATE as u64,
+                CENTER_FREQ,
+                0.0,
+                &other,
+            );
+            assert!(wrong.psr < 3.0, "PSR {} at {}s", wrong.psr, elapsed);
+            assert!(wrong.psr < DEFAULT_MIN_PSR && DEFAULT_MIN_PSR < right.psr);
+        }
     }
 }
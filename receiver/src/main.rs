@@ -1,54 +1,174 @@
 use std::fs::File;
+use std::io::Write;
 
-use crate::dsp::{Dsp, DspSettings};
-use crate::stream::{Scalar, StreamedSamplesFreqs};
+use crate::dsp::{DEFAULT_MIN_PSR, Dsp, DspSettings};
+use crate::stream::{Sample, Scalar, StreamedSamplesFreqs};
 use log::info;
 use sdriq::{Header, Sink, Source};
-use stream::load_freqs_file;
+use simulate::{SimulationSettings, Tap, parse_taps, simulate};
+use stream::{
+    load_freqs_file, load_plan_file, regenerate_freqs, regenerate_pll_changes, to_harmonic,
+};
 
 mod correlator;
+mod decimate;
 mod dsp;
+mod resample;
+mod simulate;
 mod stream;
 
 fn main() {
     env_logger::init();
     let mut pargs = pico_args::Arguments::from_env();
 
+    // Instead of processing the baseband, write a simulated one (see simulate.rs)
+    let simulation = pargs.contains("--simulate");
+
     let baseband_path: String = pargs.free_from_str().unwrap();
+
+    // Instead of a frequencies file, the transmitted frequencies may be taken from a plan file
+    // saved by the transmitter host, or regenerated from the orders file and the start epoch
+    // of the sequence (and shared secret, if any)
+    let plan_path: Option<String> = pargs.opt_value_from_str(["-p", "--plan"]).unwrap();
+    let orders_path: Option<String> = pargs.opt_value_from_str(["-o", "--orders"]).unwrap();
+    let mut pll_changes = Vec::new();
+    // Plans and orders give the fundamental, but frequency files may be of another harmonic
+    let mut freqs_harmonic = 1;
+    let mut freqs = if let Some(plan_path) = plan_path {
+        // Only needed if the plan was uploaded again at another epoch
+        let epoch: Option<i64> = pargs.opt_value_from_str(["-e", "--epoch"]).unwrap();
+        info!("Loading transmitted frequencies from plan {}", plan_path);
+
+        let (freqs, plan_pll_changes) = load_plan_file(plan_path, epoch).unwrap();
+        pll_changes = plan_pll_changes;
+        freqs
+    } else if let Some(orders_path) = orders_path {
+        let epoch: i64 = pargs.value_from_str(["-e", "--epoch"]).unwrap();
+        let secret: Option<String> = pargs.opt_value_from_str("--secret").unwrap();
+        info!(
+            "Regenerating transmitted frequencies from {} starting at epoch {}",
+            orders_path, epoch
+        );
+
+        let secret = secret.as_ref().map(|s| s.as_bytes());
+        if simulation {
+            pll_changes = regenerate_pll_changes(orders_path.clone(), epoch, secret).unwrap();
+        }
+        regenerate_freqs(orders_path, epoch, secret).unwrap()
+    } else {
+        let freqs_path: String = pargs
+            .opt_value_from_str(["-f", "--freqs"])
+            .unwrap()
+            .unwrap_or(String::from("freqs.csv"));
+        info!("Loading transmitted frequencies from {}", freqs_path);
+
+        let (freqs, harmonic) = load_freqs_file(freqs_path).unwrap();
+        freqs_harmonic = harmonic;
+        freqs
+    };
+    // The SDR may be tuned to a harmonic of the square wave output, instead of the fundamental
+    let harmonic: usize = pargs
+        .opt_value_from_str(["-n", "--harmonic"])
+        .unwrap()
+        .unwrap_or(freqs_harmonic);
+    if harmonic != 1 {
+        info!("Using harmonic {} of the transmitted frequencies", harmonic);
+        to_harmonic(&mut freqs, freqs_harmonic, harmonic).unwrap();
+    }
+
+    if simulation {
+        let taps: Option<String> = pargs.opt_value_from_str("--taps").unwrap();
+        let settings = SimulationSettings {
+            center_freq: pargs.value_from_str(["-c", "--center"]).unwrap(),
+            samp_rate: pargs.value_from_str(["-r", "--rate"]).unwrap(),
+            delay_s: pargs.opt_value_from_str("--delay").unwrap().unwrap_or(0.0),
+            clock_ppm: pargs.opt_value_from_str("--ppm").unwrap().unwrap_or(0.0),
+            foffset: pargs
+                .opt_value_from_str("--foffset")
+                .unwrap()
+                .unwrap_or(0.0),
+            taps: taps.map_or(
+                vec![Tap {
+                    delay_s: 0.0,
+                    gain: Sample::new(1.0, 0.0),
+                }],
+                |taps| parse_taps(&taps).unwrap(),
+            ),
+            snr_db: pargs.opt_value_from_str("--snr").unwrap(),
+            transient_us: pargs
+                .opt_value_from_str("--transient")
+                .unwrap()
+                .unwrap_or(planner::sequence::PLLCHANGE_US),
+            level: pargs.opt_value_from_str("--level").unwrap().unwrap_or(0.25),
+            seed: pargs.opt_value_from_str("--seed").unwrap().unwrap_or(0),
+        };
+        if pll_changes.is_empty() && settings.transient_us > 0.0 {
+            info!("PLL changes are only known from orders or plan files, no transients");
+        }
+
+        info!("Writing simulated baseband to {}", baseband_path);
+        simulate(freqs, pll_changes, &settings, baseband_path).unwrap();
+        return;
+    }
+
     info!("Loading baseband for {}", baseband_path);
     let baseband_file = File::open(baseband_path).unwrap();
     let baseband = Source::new(baseband_file).unwrap();
 
-    let freqs_path: String = pargs
-        .opt_value_from_str(["-f", "--freqs"])
-        .unwrap()
-        .unwrap_or(String::from("freqs.csv"));
-    info!("Loading transmitted frequencies from {}", freqs_path);
-
-    let freqs = load_freqs_file(freqs_path).unwrap();
     let freqs = StreamedSamplesFreqs::new(
         freqs,
         baseband.get_header().center_freq as f64,
         baseband.get_header().samp_rate,
     );
 
-    let min_psr: f64 = pargs
+    // Peak to sidelobe ratio below which a correlation is not trusted
+    let min_psr: Scalar = pargs
         .opt_value_from_str(["-m", "--minpsr"])
         .unwrap()
-        .unwrap_or(20.0);
+        .unwrap_or(DEFAULT_MIN_PSR);
 
     let output_decimate: usize = pargs
         .opt_value_from_str(["-d", "--decimate"])
         .unwrap()
         .unwrap_or(1);
+    // Band kept by the decimation, by default most of the output Nyquist band
+    let output_rate = baseband.get_header().samp_rate as f64 / output_decimate as f64;
+    let output_passband: f64 = pargs
+        .opt_value_from_str("--passband")
+        .unwrap()
+        .unwrap_or(0.4 * output_rate);
+    let output_stopband: f64 = pargs
+        .opt_value_from_str("--stopband")
+        .unwrap()
+        .unwrap_or(0.5 * output_rate);
+
+    // Re-correlate every so many seconds to follow the drift of the delay, 0 to disable
+    let track_interval_s: f64 = pargs
+        .opt_value_from_str("--track-interval")
+        .unwrap()
+        .unwrap_or(10.0);
+    // Where to write the delay versus time found by the tracking loop, as CSV
+    let track_path: Option<String> = pargs.opt_value_from_str("--track").unwrap();
+
+    // Frequency offset of the received signal, estimated if not given
+    let foffset: Option<f64> = pargs.opt_value_from_str("--foffset").unwrap();
+    let max_foffset: f64 = pargs
+        .opt_value_from_str("--max-foffset")
+        .unwrap()
+        .unwrap_or(20000.0);
 
     let dsp_settings = DspSettings {
         window_size: 512,
         window_step: 256,
         spectrogram_size_search: 20000 * 2,
         spectrogram_size_adjust: 5000,
+        track_interval_s,
         output_decimate,
-        min_psr: min_psr as Scalar,
+        output_passband,
+        output_stopband,
+        min_psr,
+        foffset,
+        max_foffset,
     };
 
     let header = Header {
@@ -58,12 +178,34 @@ fn main() {
         samp_size: 24,
     };
 
-    let mut dsp = Dsp::new(baseband, freqs.unwrap(), dsp_settings);
-    let samples = dsp.run(10000000).unwrap();
+    let samp_rate = baseband.get_header().samp_rate;
+    let mut dsp = Dsp::new(baseband, freqs.unwrap(), dsp_settings).unwrap();
 
     let file = File::create("file.sdriq").unwrap();
 
     let mut sink = Sink::new(file, header).unwrap();
-    sink.write_all_samples_denorm(samples.as_slice().unwrap())
-        .unwrap();
+    let num_written = dsp.run_to_end(&mut sink).unwrap();
+    info!("Written {} samples to file.sdriq", num_written);
+    info!("Frequency offset: {:.1}Hz", dsp.get_foffset());
+    match dsp.get_ppm() {
+        Some(ppm) => info!("Baseband sample rate offset: {:.2}ppm", ppm),
+        None => info!("Baseband sample rate offset could not be estimated"),
+    }
+
+    if let Some(track_path) = track_path {
+        let mut file = File::create(&track_path).unwrap();
+        writeln!(file, "t,delay_samples,delay_s,psr").unwrap();
+        for point in dsp.get_track() {
+            writeln!(
+                file,
+                "{},{},{},{}",
+                point.t,
+                point.delay,
+                point.delay / samp_rate as f64,
+                point.psr
+            )
+            .unwrap();
+        }
+        info!("Written delay track to {}", track_path);
+    }
 }
@@ -0,0 +1,149 @@
+//! Fractional resampling of the baseband, to compensate the sample rate offset between the SDR
+//! and the transmitter. A Farrow structure evaluates a cubic Lagrange interpolator at arbitrary
+//! positions of the input, so the ratio can be changed at any time without redesigning filters.
+
+use ndarray::prelude::*;
+
+use crate::stream::{Sample, Scalar};
+
+/// Input samples kept from the previous chunk, as the interpolator needs one sample before the
+/// interpolated position and two after it
+const HISTORY: usize = 3;
+
+pub struct FarrowResampler {
+    /// Input samples consumed per output sample
+    ratio: f64,
+    /// Position of the next output sample, in samples of the history followed by the next input
+    pos: f64,
+    history: [Sample; HISTORY],
+    /// Input samples skipped (or repeated, if negative) with respect to a ratio of one
+    drift: f64,
+}
+
+impl FarrowResampler {
+    pub fn new() -> Self {
+        Self {
+            ratio: 1.0,
+            pos: HISTORY as f64,
+            history: [Sample::new(0.0, 0.0); HISTORY],
+            drift: 0.0,
+        }
+    }
+
+    pub fn set_ratio(&mut self, ratio: f64) {
+        self.ratio = ratio;
+    }
+
+    /// Input samples skipped so far because of the ratio, so the total shift of the output
+    pub fn get_drift(&self) -> f64 {
+        self.drift
+    }
+
+    /// Position of the next output sample relative to the next input sample, so zero or
+    /// negative as the last input samples are kept until the following ones arrive
+    pub fn get_position(&self) -> f64 {
+        self.pos - HISTORY as f64
+    }
+
+    /// Resamples the next `input` samples, returning as many output samples as they allow.
+    /// The last input samples are kept to interpolate the first output of the next call.
+    pub fn process(&mut self, input: ArrayView1<Sample>) -> Array1<Sample> {
+        let buffer: Vec<Sample> = self.history.iter().chain(input.iter()).copied().collect();
+        let mut out = Vec::with_capacity((input.len() as f64 / self.ratio) as usize + 1);
+
+        while (self.pos as usize) + 2 < buffer.len() {
+            let i = self.pos as usize;
+            let mu = (self.pos - i as f64) as Scalar;
+            let (xm1, x0, x1, x2) = (buffer[i - 1], buffer[i], buffer[i + 1], buffer[i + 2]);
+
+            // Cubic Lagrange interpolation, with the polynomial evaluated by Horner's method
+            let c1 = x1 - xm1 / 3.0 - x0 / 2.0 - x2 / 6.0;
+            let c2 = (xm1 + x1) / 2.0 - x0;
+            let c3 = (x2 - xm1) / 6.0 + (x0 - x1) / 2.0;
+            out.push(((c3 * mu + c2) * mu + c1) * mu + x0);
+
+            self.pos += self.ratio;
+            self.drift += self.ratio - 1.0;
+        }
+
+        self.pos -= (buffer.len() - HISTORY) as f64;
+        self.history
+            .copy_from_slice(&buffer[buffer.len() - HISTORY..]);
+
+        Array1::from(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tone(n: usize, freq: f64) -> Array1<Sample> {
+        (0..n)
+            .map(|i| Sample::from_polar(1.0, (std::f64::consts::TAU * freq * i as f64) as Scalar))
+            .collect()
+    }
+
+    #[test]
+    fn unit_ratio_passes_input_through() {
+        let input = tone(100, 0.05);
+        let mut resampler = FarrowResampler::new();
+
+        let out = resampler.process(input.view());
+        // The last samples are held until the next call
+        assert_eq!(out.len(), input.len() - 2);
+        for (a, b) in out.iter().zip(&input) {
+            assert!((a - b).norm() < 1e-6);
+        }
+        assert_eq!(resampler.get_drift(), 0.0);
+    }
+
+    #[test]
+    fn chunked_output_matches_single_call() {
+        let input = tone(10_000, 0.013);
+        let ratio = 1.0 + 37e-6;
+
+        let mut single = FarrowResampler::new();
+        single.set_ratio(ratio);
+        let expected = single.process(input.view());
+
+        let mut chunked = FarrowResampler::new();
+        chunked.set_ratio(ratio);
+        let mut out = Vec::new();
+        let mut from = 0;
+        for len in [1, 2, 700, 3, 4096].into_iter().cycle() {
+            if from == input.len() {
+                break;
+            }
+            let to = (from + len).min(input.len());
+            out.extend(chunked.process(input.slice(s![from..to])));
+            from = to;
+        }
+
+        assert_eq!(out.len(), expected.len());
+        for (a, b) in out.iter().zip(&expected) {
+            assert!((a - b).norm() < 1e-6);
+        }
+        assert!((chunked.get_drift() - single.get_drift()).abs() < 1e-9);
+        assert!((chunked.get_position() - single.get_position()).abs() < 1e-9);
+    }
+
+    #[test]
+    fn interpolates_between_samples() {
+        // Skipping a fraction of a sample per output delays a slow tone by that much
+        let freq = 0.01;
+        let ratio = 1.25;
+        let input = tone(1000, freq);
+        let mut resampler = FarrowResampler::new();
+        resampler.set_ratio(ratio);
+
+        let out = resampler.process(input.view());
+        for (i, sample) in out.iter().enumerate() {
+            let expected = Sample::from_polar(
+                1.0,
+                (std::f64::consts::TAU * freq * i as f64 * ratio) as Scalar,
+            );
+            assert!((sample - expected).norm() < 1e-4, "sample {i}");
+        }
+    }
+}
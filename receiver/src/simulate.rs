@@ -0,0 +1,227 @@
+//! Generation of a simulated received baseband from the transmitted frequencies, to test the
+//! whole receiver pipeline without hardware. The received signal is synthesized with the same
+//! phase-continuous generator used for the reference, and then impaired with the transmitter
+//! clock error, a frequency offset, PLL change transients, multipath, delay and noise.
+
+use anyhow::{Result, anyhow};
+use ndarray::prelude::*;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+use std::fs::File;
+
+use crate::stream::{FreqChange, Sample, Scalar, StreamedSamplesFreqs, apply_clock_offset};
+
+/// Time recorded before the start of the sequence, so the receiver can seek before it
+const PREROLL_S: f64 = 1.0;
+
+/// Number of samples generated at once
+const CHUNK_SIZE: usize = 65536;
+
+/// A multipath component
+pub struct Tap {
+    pub delay_s: f64,
+    pub gain: Sample,
+}
+
+pub struct SimulationSettings {
+    pub center_freq: f64,
+    pub samp_rate: u32,
+
+    // Propagation delay, rounded to whole samples
+    pub delay_s: f64,
+    // Error of the transmitter clock, in parts per million
+    pub clock_ppm: f64,
+    // Offset of the receiver tuning, in Hz
+    pub foffset: f64,
+    // Multipath components, a single one with no delay and unit gain for a clean channel
+    pub taps: Vec<Tap>,
+    // Signal to noise ratio over the whole sample rate, or no noise at all
+    pub snr_db: Option<f64>,
+    // Time the output is off after each PLL change, which also randomizes the carrier phase.
+    // Transients are not simulated if zero.
+    pub transient_us: f64,
+    // Amplitude of the carrier, relative to full scale
+    pub level: f64,
+    // Seed for the noise and the phase after PLL changes
+    pub seed: u64,
+}
+
+/// Parses multipath taps given as comma separated `delay_us:gain_dB[:phase_deg]`
+pub fn parse_taps(taps: &str) -> Result<Vec<Tap>> {
+    let mut out = Vec::new();
+
+    for tap in taps.split(',') {
+        let values: Vec<f64> = tap
+            .split(':')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("Invalid tap '{}'", tap))?;
+
+        let (delay_us, gain_db, phase_deg) = match values[..] {
+            [delay_us, gain_db] => (delay_us, gain_db, 0.0),
+            [delay_us, gain_db, phase_deg] => (delay_us, gain_db, phase_deg),
+            _ => return Err(anyhow!("Invalid tap '{}'", tap)),
+        };
+        if delay_us < 0.0 {
+            return Err(anyhow!("Tap delay must not be negative '{}'", tap));
+        }
+
+        out.push(Tap {
+            delay_s: delay_us * 1e-6,
+            gain: Sample::from_polar(
+                10.0f64.powf(gain_db / 20.0) as Scalar,
+                phase_deg.to_radians() as Scalar,
+            ),
+        });
+    }
+
+    Ok(out)
+}
+
+/// Mutes the output and randomizes the phase after each PLL change
+struct Transients {
+    changes: Vec<f64>,
+    next: usize,
+    duration_s: f64,
+    phase: f64,
+}
+
+impl Transients {
+    fn apply(&mut self, t: f64, sample: Sample, rng: &mut ChaCha20Rng) -> Sample {
+        while self.next < self.changes.len() && self.changes[self.next] <= t {
+            self.next += 1;
+            self.phase = rng.random::<f64>() * 2.0 * std::f64::consts::PI;
+        }
+
+        if self.next > 0 && t < self.changes[self.next - 1] + self.duration_s {
+            Sample::new(0.0, 0.0)
+        } else {
+            sample * Sample::from_polar(1.0, self.phase as Scalar)
+        }
+    }
+}
+
+/// Standard normal sample, using the Box-Muller transform
+fn gaussian(rng: &mut ChaCha20Rng) -> f64 {
+    let u1 = 1.0 - rng.random::<f64>();
+    let u2 = rng.random::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+}
+
+/// Write the baseband received from `freqs` to an sdriq file. `pll_changes` are the start
+/// times of the PLL changes of the sequence, as given by `regenerate_pll_changes`.
+pub fn simulate(
+    mut freqs: Vec<FreqChange>,
+    pll_changes: Vec<f64>,
+    settings: &SimulationSettings,
+    path: String,
+) -> Result<()> {
+    let samp_rate = settings.samp_rate as f64;
+    let tstep = 1.0 / samp_rate;
+
+    apply_clock_offset(&mut freqs, settings.clock_ppm);
+    let mut synth = StreamedSamplesFreqs::new(freqs, settings.center_freq, settings.samp_rate)?;
+    let first_epoch = synth.get_first_epoch();
+
+    let clock_fac = 1.0 + settings.clock_ppm * 1e-6;
+    let mut transients = (settings.transient_us > 0.0).then(|| Transients {
+        changes: pll_changes
+            .iter()
+            .map(|t| first_epoch + (t - first_epoch) / clock_fac)
+            .collect(),
+        next: 0,
+        duration_s: settings.transient_us * 1e-6,
+        phase: 0.0,
+    });
+
+    let taps: Vec<(usize, Sample)> = settings
+        .taps
+        .iter()
+        .map(|tap| ((tap.delay_s * samp_rate).round() as usize, tap.gain))
+        .collect();
+    let max_delay = taps.iter().map(|tap| tap.0).max().unwrap_or(0);
+    // Past input samples, needed by the delayed taps
+    let mut history: Vec<Sample> = vec![Sample::new(0.0, 0.0); max_delay];
+
+    let noise_std = settings.snr_db.map_or(0.0, |snr| {
+        settings.level * (0.5 * 10.0f64.powf(-snr / 10.0)).sqrt()
+    });
+    let mut rng = ChaCha20Rng::seed_from_u64(settings.seed);
+
+    let header = sdriq::Header {
+        samp_rate: settings.samp_rate,
+        center_freq: settings.center_freq as u64,
+        start_timestamp: ((first_epoch - PREROLL_S) * 1000.0) as u64,
+        samp_size: 24,
+    };
+    let file = File::create(&path)?;
+    let mut sink = sdriq::Sink::new(file, header)?;
+
+    // Samples before the signal arrives, and after it ends so the multipath echoes are flushed
+    let mut lead = ((PREROLL_S + settings.delay_s) * samp_rate).round() as usize;
+    let mut tail = max_delay;
+    let mut synthesized = 0;
+    let mut signal_done = false;
+    let mut num_written = 0;
+
+    loop {
+        let mut input: Array1<Sample> = Array1::zeros(CHUNK_SIZE);
+        let mut n = lead.min(CHUNK_SIZE);
+        lead -= n;
+
+        if n < CHUNK_SIZE && !signal_done {
+            let (samples, num_read) = synth.get_next(CHUNK_SIZE - n, settings.foffset);
+            for (i, &sample) in samples.iter().take(num_read).enumerate() {
+                let t = first_epoch + (synthesized + i) as f64 * tstep;
+                input[n + i] = match &mut transients {
+                    Some(transients) => transients.apply(t, sample, &mut rng),
+                    None => sample,
+                };
+            }
+            signal_done = num_read < CHUNK_SIZE - n;
+            synthesized += num_read;
+            n += num_read;
+        }
+
+        if signal_done {
+            let num_tail = tail.min(CHUNK_SIZE - n);
+            tail -= num_tail;
+            n += num_tail;
+        }
+
+        if n == 0 {
+            break;
+        }
+
+        let extended: Vec<Sample> = history
+            .iter()
+            .chain(input.slice(s![..n]).iter())
+            .copied()
+            .collect();
+        let mut output: Array1<Sample> = Array1::zeros(n);
+        for (i, out) in output.iter_mut().enumerate() {
+            let received: Sample = taps
+                .iter()
+                .map(|&(delay, gain)| gain * extended[max_delay + i - delay])
+                .sum();
+            let noise = Sample::new(
+                (noise_std * gaussian(&mut rng)) as Scalar,
+                (noise_std * gaussian(&mut rng)) as Scalar,
+            );
+            *out = received * settings.level as Scalar + noise;
+        }
+        history.copy_from_slice(&extended[n..]);
+
+        sink.write_all_samples_denorm(output.as_slice().expect("Flat memory"))?;
+        num_written += n;
+    }
+
+    log::info!(
+        "Written {} simulated samples ({}s) to {}",
+        num_written,
+        num_written as f64 * tstep,
+        path
+    );
+
+    Ok(())
+}
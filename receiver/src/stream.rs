@@ -17,29 +17,142 @@ pub type Sample = Complex<Scalar>;
 pub struct FreqChange {
     t: f64,
     freq: f64,
+    /// Amplitude relative to the fundamental of the square wave output
+    amp: f64,
 }
 
-/// Load frequency info from a frequencies CSV file
-pub fn load_freqs_file(freqs_path: String) -> Result<Vec<FreqChange>> {
+/// Load frequency info from a frequencies CSV file. The amplitude column is optional, and
+/// taken as 1 if missing. Also returns the harmonic the frequencies were written for, as
+/// recorded in a `# harmonic, n` line, or 1 if there's none.
+pub fn load_freqs_file(freqs_path: String) -> Result<(Vec<FreqChange>, usize)> {
     let mut out = Vec::new();
+    let mut harmonic = 1;
     let lines = BufReader::new(File::open(freqs_path)?).lines();
-    let re = Regex::new(r"\s*([0-9.]+)\s*,([0-9.]+)")?;
+    let re = Regex::new(r"\s*([0-9.]+)\s*,\s*([0-9.]+)(?:\s*,\s*([0-9.]+))?")?;
+    let harmonic_re = Regex::new(r"^\s*#\s*harmonic\s*,\s*([0-9]+)")?;
 
     for maybe_line in lines {
         let line = maybe_line?;
+        if line.trim_start().starts_with('#') {
+            if let Some(regex_match) = harmonic_re.captures(line.as_str()) {
+                harmonic = regex_match.get(1).expect("Regex").as_str().parse()?;
+            }
+            continue;
+        }
         let regex_match = re.captures(line.as_str()).ok_or(anyhow!("Wrong regex"))?;
         let t = regex_match.get(1).expect("Regex").as_str().parse()?;
         let freq = regex_match.get(2).expect("Regex").as_str().parse()?;
+        let amp = match regex_match.get(3) {
+            Some(amp) => amp.as_str().parse()?,
+            None => 1.0,
+        };
+
+        out.push(FreqChange { t, freq, amp });
+    }
+
+    Ok((out, harmonic))
+}
+
+/// Regenerate the transmitted frequencies from the orders file and start epoch, using the
+/// same generation code as the transmitter host
+pub fn regenerate_freqs(
+    orders_path: String,
+    start_epoch: i64,
+    secret: Option<&[u8]>,
+) -> Result<Vec<FreqChange>> {
+    let orders = planner::orders::parse_orders(std::fs::read_to_string(orders_path)?)?;
+    let seqs =
+        planner::sequence::build_sequences(&orders, start_epoch, secret).map_err(|e| anyhow!(e))?;
+
+    Ok(
+        planner::sequence::build_frequencies(seqs.iter().map(|s| &s.seq), start_epoch)
+            .into_iter()
+            .map(|(t, freq)| FreqChange { t, freq, amp: 1.0 })
+            .collect(),
+    )
+}
+
+/// Load the transmitted frequencies and PLL change times from a plan file saved by the
+/// transmitter host, exactly as they were uploaded. The sequence starts at the epoch stored in
+/// the file, unless it was replayed at `start_epoch`.
+pub fn load_plan_file(
+    plan_path: String,
+    start_epoch: Option<i64>,
+) -> Result<(Vec<FreqChange>, Vec<f64>)> {
+    let saved = planner::planfile::PlanFile::from_json(&std::fs::read_to_string(plan_path)?)?;
+    let plan = saved.upload_plan()?;
+    let epoch = start_epoch.unwrap_or(saved.start_epoch);
+
+    let freqs = planner::sequence::build_frequencies_fref(plan.values(), epoch, saved.fref_hz)
+        .into_iter()
+        .map(|(t, freq)| FreqChange { t, freq, amp: 1.0 })
+        .collect();
+    let pll_changes = planner::sequence::build_pll_changes(plan.values(), epoch);
+
+    Ok((freqs, pll_changes))
+}
 
-        out.push(FreqChange { t, freq });
+/// Moves the frequencies, currently of harmonic `current`, to the given harmonic of the square
+/// wave output, for when the receiver is tuned to a harmonic instead of the fundamental. Only
+/// fundamental frequencies can be moved, so the harmonic is never applied twice.
+pub fn to_harmonic(freqs: &mut [FreqChange], current: usize, harmonic: usize) -> Result<()> {
+    if harmonic == 0 || harmonic.is_multiple_of(2) {
+        return Err(anyhow!(
+            "Harmonic must be odd, square waves have no even harmonics"
+        ));
+    }
+    if harmonic == current {
+        return Ok(());
+    }
+    if current != 1 {
+        return Err(anyhow!(
+            "Frequencies are already of harmonic {}, they can't be moved to harmonic {}",
+            current,
+            harmonic
+        ));
     }
 
-    Ok(out)
+    let amp = planner::sequence::harmonic_amplitude(harmonic);
+    for change in freqs {
+        change.freq *= harmonic as f64;
+        change.amp *= amp;
+    }
+
+    Ok(())
+}
+
+/// Regenerate the start times of the PLL changes of the transmitted sequence, see
+/// `regenerate_freqs`
+pub fn regenerate_pll_changes(
+    orders_path: String,
+    start_epoch: i64,
+    secret: Option<&[u8]>,
+) -> Result<Vec<f64>> {
+    let orders = planner::orders::parse_orders(std::fs::read_to_string(orders_path)?)?;
+    let seqs =
+        planner::sequence::build_sequences(&orders, start_epoch, secret).map_err(|e| anyhow!(e))?;
+
+    Ok(planner::sequence::build_pll_changes(
+        seqs.iter().map(|s| &s.seq),
+        start_epoch,
+    ))
+}
+
+/// Applies an error of the transmitter clock, which scales all frequencies and stretches time
+/// from the first frequency change
+pub fn apply_clock_offset(freqs: &mut [FreqChange], ppm: f64) {
+    let fac = 1.0 + ppm * 1e-6;
+    let t0 = freqs[0].t;
+    for change in freqs {
+        change.freq *= fac;
+        change.t = t0 + (change.t - t0) / fac;
+    }
 }
 
 /// A frequency, and its start and end time
 pub struct FreqOnTimes {
     pub freq: f64,
+    pub amp: f64,
     pub start: f64,
     pub end: f64,
 }
@@ -55,6 +168,7 @@ pub fn get_freqs_for_interval(freqs: &Vec<FreqChange>, start: f64, dur: f64) ->
 
         out.push(FreqOnTimes {
             freq: pair[0].freq,
+            amp: pair[0].amp,
             start: pair[0].t,
             end: pair[1].t,
         });
@@ -63,6 +177,13 @@ pub fn get_freqs_for_interval(freqs: &Vec<FreqChange>, start: f64, dur: f64) ->
     out
 }
 
+/// Returns the frequency changes needed to synthesize the interval from `start` to `end`
+pub fn get_freqs_around(freqs: &[FreqChange], start: f64, end: f64) -> Vec<FreqChange> {
+    let first = freqs.partition_point(|f| f.t <= start).saturating_sub(1);
+    let last = freqs.partition_point(|f| f.t <= end);
+    freqs[first..(last + 1).min(freqs.len())].to_vec()
+}
+
 /// Returns current, and next freq change for given time
 pub fn find_freq_change_for(freqs: &Vec<FreqChange>, t: f64) -> Option<(FreqChange, FreqChange)> {
     freqs
@@ -77,7 +198,13 @@ pub fn find_freq_change_for(freqs: &Vec<FreqChange>, t: f64) -> Option<(FreqChan
 pub struct StreamedSamplesFreqs {
     /// Internal state, absolute time of the "synthesizer"
     t: f64,
-    /// Internal state, phase of the "synthesizer", monotonically increasing
+    /// Internal state, time the synthesizer was started or sought to, and samples generated
+    /// since, so `t` doesn't accumulate rounding errors (at current epochs, an ulp of `t` is a
+    /// sizable fraction of a sample)
+    base_t: f64,
+    num_generated: u64,
+    /// Internal state, phase of the "synthesizer", kept within [0, 2pi) so it doesn't lose
+    /// precision on long runs
     phase: f64,
     /// Internal state, timestep to use (inverse of sample rate)
     tstep: f64,
@@ -97,8 +224,15 @@ impl StreamedSamplesFreqs {
         self.center_freq
     }
 
+    /// Returns the epoch of the next sample generated
+    pub fn get_epoch(&self) -> f64 {
+        self.t
+    }
+
     pub fn seek_epoch(&mut self, t: f64) {
         self.t = t;
+        self.base_t = t;
+        self.num_generated = 0;
     }
 
     /// Return hypothetical baseband data for the reference frequencies.
@@ -125,9 +259,8 @@ impl StreamedSamplesFreqs {
 
                 let rf = pair.0.freq - self.center_freq + foffset;
                 let w = 2.0 * std::f64::consts::PI * rf;
-                self.phase += w * self.tstep;
-                out[num_written] =
-                    Sample::new(self.phase.cos() as Scalar, self.phase.sin() as Scalar);
+                self.phase = (self.phase + w * self.tstep).rem_euclid(std::f64::consts::TAU);
+                out[num_written] = Sample::from_polar(pair.0.amp as Scalar, self.phase as Scalar);
 
                 num_written += 1;
                 this_step_written += 1;
@@ -135,7 +268,8 @@ impl StreamedSamplesFreqs {
             }
 
             // Do it here instead
-            self.t += self.tstep * this_step_written as f64;
+            self.num_generated += this_step_written as u64;
+            self.t = self.base_t + self.tstep * self.num_generated as f64;
         }
 
         (out, num_written)
@@ -145,6 +279,8 @@ impl StreamedSamplesFreqs {
     pub fn new(freqs: Vec<FreqChange>, center_freq: f64, srate: u32) -> Result<Self> {
         Ok(Self {
             t: freqs[0].t,
+            base_t: freqs[0].t,
+            num_generated: 0,
             center_freq,
             tstep: 1.0 / (srate as f64),
             freqs,
@@ -179,3 +315,55 @@ impl StreamedSamplesFreqs {
         Ok(())
     }
 }
+
+/// Frequencies and PLL change times planned for `orders` starting at `start_epoch`, as
+/// `regenerate_freqs` and `regenerate_pll_changes` would load them from an orders file
+#[cfg(test)]
+pub fn freqs_from_orders(orders: &str, start_epoch: i64) -> (Vec<FreqChange>, Vec<f64>) {
+    let orders = planner::orders::parse_orders(orders.to_string()).unwrap();
+    let seqs = planner::sequence::build_sequences(&orders, start_epoch, None).unwrap();
+
+    let freqs = planner::sequence::build_frequencies(seqs.iter().map(|s| &s.seq), start_epoch)
+        .into_iter()
+        .map(|(t, freq)| FreqChange { t, freq, amp: 1.0 })
+        .collect();
+    let pll_changes =
+        planner::sequence::build_pll_changes(seqs.iter().map(|s| &s.seq), start_epoch);
+    (freqs, pll_changes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn synthesized_phase_stays_coherent() {
+        // A large offset makes the phase grow by ~1e9 rad, where an f32 is useless
+        const SAMP_RATE: u32 = 2_000_000;
+        const OFFSET_HZ: f64 = 1_000_012_345.6;
+        let freqs = vec![
+            FreqChange {
+                t: 0.0,
+                freq: OFFSET_HZ,
+                amp: 1.0,
+            },
+            FreqChange {
+                t: 1.0,
+                freq: OFFSET_HZ,
+                amp: 1.0,
+            },
+        ];
+        let mut stream = StreamedSamplesFreqs::new(freqs, 0.0, SAMP_RATE).unwrap();
+
+        let n = 400_000;
+        let (samples, written) = stream.get_next(n, 0.0);
+        assert_eq!(written, n);
+
+        for i in [n - 3, n - 2, n - 1] {
+            let cycles = OFFSET_HZ * (i + 1) as f64 / SAMP_RATE as f64;
+            let phase = std::f64::consts::TAU * cycles.fract();
+            let expected = Sample::new(phase.cos() as Scalar, phase.sin() as Scalar);
+            assert!((samples[i] - expected).norm() < 1e-3, "sample {i}");
+        }
+    }
+}
@@ -0,0 +1,291 @@
+// Delay-Doppler ambiguity of a planned hop timeline, as seen by the receiver's spectrogram
+// correlator. The correlator sums, over every frequency bin, the cross-correlation in time of
+// the received and reference spectrogram lines. Received with a frequency offset, each line is
+// correlated against the reference line some bins away. So the autocorrelation of the
+// reference spectrogram, shifted in time (delay) and in bins (Doppler), is what the
+// correlator sees for a clean received signal.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use ndarray::Array2;
+
+// Speed of light, to express delays as path lengths
+const C_M_S: f64 = 299_792_458.0;
+
+// Fraction of the peak where the resolution and tolerance are measured
+const HALF_POWER: f64 = 0.5;
+
+pub struct AmbiguitySettings {
+    pub samp_rate: f64,
+    // Spectrogram parameters, in samples, as used by the receiver
+    pub window_size: usize,
+    pub window_step: usize,
+    // Number of windows analysed from the start of the plan, as in the receiver search phase
+    pub num_windows: usize,
+    // Largest frequency offset analysed, in spectrogram bins
+    pub max_doppler_bins: usize,
+}
+
+impl Default for AmbiguitySettings {
+    fn default() -> Self {
+        AmbiguitySettings {
+            samp_rate: 2_000_000.0,
+            window_size: 512,
+            window_step: 256,
+            num_windows: 40_000,
+            max_doppler_bins: 16,
+        }
+    }
+}
+
+// Part of a hop on a single spectrogram bin, over windows [start, end)
+struct Entry {
+    start: i64,
+    end: i64,
+    weight: f64,
+}
+
+pub struct Ambiguity {
+    // Time between windows, in s
+    pub delay_step_s: f64,
+    // Width of a bin, in Hz
+    pub doppler_step_hz: f64,
+    // Correlation for each Doppler (rows, in bins, in FFT order: 0, 1, ..., -1) and delay
+    // (columns, from -num_windows + 1 to num_windows - 1 windows), relative to the peak
+    pub surface: Array2<f32>,
+}
+
+// Splits the hops into spectrogram bin entries, sharing each hop between its two nearest bins
+fn build_entries(freqs: &[(f64, f64)], settings: &AmbiguitySettings) -> BTreeMap<i64, Vec<Entry>> {
+    let delay_step_s = settings.window_step as f64 / settings.samp_rate;
+    let doppler_step_hz = settings.samp_rate / settings.window_size as f64;
+    let num_windows = settings.num_windows as i64;
+
+    let t0 = freqs[0].0;
+    let (fmin, fmax) = freqs
+        .iter()
+        .fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), &(_, f)| {
+            (lo.min(f), hi.max(f))
+        });
+    let center = (fmin + fmax) / 2.0;
+
+    let mut out: BTreeMap<i64, Vec<Entry>> = BTreeMap::new();
+
+    for pair in freqs.windows(2) {
+        let start = ((pair[0].0 - t0) / delay_step_s).floor() as i64;
+        let end = (((pair[1].0 - t0) / delay_step_s).ceil() as i64).min(num_windows);
+        if start >= num_windows {
+            break;
+        }
+        if start >= end {
+            continue;
+        }
+
+        let fract_bin = (pair[0].1 - center) / doppler_step_hz;
+        let lower = fract_bin.floor();
+        let upper_weight = fract_bin - lower;
+        for (bin, weight) in [
+            (lower as i64, 1.0 - upper_weight),
+            (lower as i64 + 1, upper_weight),
+        ] {
+            if weight > 0.0 {
+                out.entry(bin)
+                    .or_default()
+                    .push(Entry { start, end, weight });
+            }
+        }
+    }
+
+    out
+}
+
+impl Ambiguity {
+    // Computes the ambiguity of the hop timeline given as (start time, frequency) pairs
+    pub fn new(freqs: &[(f64, f64)], settings: &AmbiguitySettings) -> Result<Self, &'static str> {
+        if freqs.len() < 2 {
+            return Err("Too few hops to analyse");
+        }
+
+        let entries = build_entries(freqs, settings);
+        let max_doppler = settings.max_doppler_bins as i64;
+        let max_lag = settings.num_windows as i64 - 1;
+        let num_rows = 2 * max_doppler as usize + 1;
+        let mut surface: Array2<f64> = Array2::zeros((num_rows, 2 * max_lag as usize + 1));
+
+        // Each pair of entries contributes their overlap when one is delayed by the lag
+        for (&bin_a, entries_a) in &entries {
+            for (&bin_b, entries_b) in entries.range(bin_a - max_doppler..=bin_a + max_doppler) {
+                let doppler = bin_b - bin_a;
+                let row = doppler.rem_euclid(num_rows as i64) as usize;
+
+                for a in entries_a {
+                    for b in entries_b {
+                        let weight = a.weight * b.weight;
+                        let min_lag = (b.start - a.end + 1).max(-max_lag);
+                        let max_lag_pair = (b.end - a.start - 1).min(max_lag);
+                        for lag in min_lag..=max_lag_pair {
+                            let overlap = a.end.min(b.end - lag) - a.start.max(b.start - lag);
+                            surface[(row, (lag + max_lag) as usize)] += weight * overlap as f64;
+                        }
+                    }
+                }
+            }
+        }
+
+        let peak = surface[(0, max_lag as usize)];
+        if peak <= 0.0 {
+            return Err("No hops in the analysed span");
+        }
+
+        Ok(Ambiguity {
+            delay_step_s: settings.window_step as f64 / settings.samp_rate,
+            doppler_step_hz: settings.samp_rate / settings.window_size as f64,
+            surface: surface.mapv(|v| (v / peak) as f32),
+        })
+    }
+
+    fn max_lag(&self) -> usize {
+        self.surface.ncols() / 2
+    }
+
+    fn doppler_row(&self, doppler: i64) -> usize {
+        doppler.rem_euclid(self.surface.nrows() as i64) as usize
+    }
+
+    // Lags (relative to zero) around the peak where the zero Doppler cut keeps decreasing
+    fn main_lobe(&self) -> (usize, usize) {
+        let row = self.surface.row(0);
+        let center = self.max_lag();
+
+        let mut before = 0;
+        while before < center && row[center - before - 1] <= row[center - before] {
+            before += 1;
+        }
+        let mut after = 0;
+        while after < center && row[center + after + 1] <= row[center + after] {
+            after += 1;
+        }
+
+        (before, after)
+    }
+
+    // Peak to highest sidelobe ratio, out of the main lobe, with or without frequency offsets
+    fn psr(&self, any_doppler: bool) -> f64 {
+        let (before, after) = self.main_lobe();
+        let center = self.max_lag();
+
+        let rows = if any_doppler {
+            0..self.surface.nrows()
+        } else {
+            0..1
+        };
+
+        let mut sidelobe: f32 = 0.0;
+        for row in rows {
+            for (col, &v) in self.surface.row(row).iter().enumerate() {
+                if col + before < center || col > center + after {
+                    sidelobe = sidelobe.max(v);
+                }
+            }
+        }
+
+        1.0 / sidelobe as f64
+    }
+
+    // Width of the zero Doppler cut above half its peak
+    pub fn delay_resolution_s(&self) -> f64 {
+        let row = self.surface.row(0);
+        let center = self.max_lag();
+
+        // Distance from the peak to the half power point, interpolated
+        let half_width = |step: isize| {
+            let mut i = 0;
+            loop {
+                let next = (center as isize + (i + 1) * step) as usize;
+                if next >= row.len() || i as usize >= center {
+                    return i as f64;
+                }
+                let (v0, v1) = (row[(center as isize + i * step) as usize], row[next]);
+                if (v1 as f64) < HALF_POWER {
+                    return i as f64 + (v0 as f64 - HALF_POWER) / (v0 - v1) as f64;
+                }
+                i += 1;
+            }
+        };
+
+        (half_width(-1) + half_width(1)) * self.delay_step_s
+    }
+
+    // Largest frequency offset (of either sign) keeping the main lobe peak above half
+    pub fn doppler_tolerance_hz(&self) -> f64 {
+        let (before, after) = self.main_lobe();
+        let center = self.max_lag();
+        let lobe_peak = |doppler: i64| -> f64 {
+            let row = self.surface.row(self.doppler_row(doppler));
+            row.slice(ndarray::s![center - before..=center + after])
+                .fold(0.0f32, |a, &b| a.max(b)) as f64
+        };
+
+        let max_doppler = (self.surface.nrows() / 2) as i64;
+        let mut prev = 1.0;
+        for doppler in 1..=max_doppler {
+            let v = lobe_peak(doppler).min(lobe_peak(-doppler));
+            if v < HALF_POWER {
+                let fract = (prev - HALF_POWER) / (prev - v);
+                return (doppler as f64 - 1.0 + fract) * self.doppler_step_hz;
+            }
+            prev = v;
+        }
+
+        max_doppler as f64 * self.doppler_step_hz
+    }
+
+    pub fn summary(&self) -> String {
+        let mut out = String::new();
+        let psr_db = |psr: f64| 10.0 * psr.log10();
+        let resolution_s = self.delay_resolution_s();
+
+        writeln!(
+            &mut out,
+            "Ambiguity: PSR {:.2}dB at zero Doppler, {:.2}dB within ±{:.0}Hz",
+            psr_db(self.psr(false)),
+            psr_db(self.psr(true)),
+            (self.surface.nrows() / 2) as f64 * self.doppler_step_hz
+        )
+        .unwrap();
+        writeln!(
+            &mut out,
+            "Ambiguity: delay resolution {:.1}us ({:.1}km), Doppler tolerance ±{:.0}Hz",
+            resolution_s * 1e6,
+            resolution_s * C_M_S * 1e-3,
+            self.doppler_tolerance_hz()
+        )
+        .unwrap();
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hopping_resolves_delay() {
+        let settings = AmbiguitySettings {
+            num_windows: 1000,
+            ..Default::default()
+        };
+        let tone = [(0.0, 7e6), (1.0, 7e6)];
+        let hops: Vec<(f64, f64)> = (0..200)
+            .map(|i| (i as f64 * 5e-4, 7e6 + ((i * i) % 251) as f64 * 4000.0))
+            .collect();
+
+        let tone = Ambiguity::new(&tone, &settings).unwrap();
+        let hops = Ambiguity::new(&hops, &settings).unwrap();
+
+        assert!(tone.delay_resolution_s() > 100.0 * hops.delay_resolution_s());
+        assert!(hops.psr(false) > 2.0);
+    }
+}
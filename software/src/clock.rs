@@ -0,0 +1,98 @@
+// Status of the host clock, as reported by the local chrony daemon. The sequences are seeded
+// and timed by the host clock, so it must agree with the receiver's one.
+
+use std::fmt;
+use std::process::Command;
+
+pub struct ClockStatus {
+    // Reference the clock is synchronised to
+    pub reference: String,
+    pub stratum: u32,
+    // System clock minus true time
+    pub offset_s: f64,
+    // Bound of the error of the system clock, including the uncertainty of the reference
+    pub max_error_s: f64,
+    pub synchronised: bool,
+}
+
+impl fmt::Display for ClockStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "offset {:.3}ms (max error {:.3}ms) from {} at stratum {}{}",
+            self.offset_s * 1e3,
+            self.max_error_s * 1e3,
+            self.reference,
+            self.stratum,
+            if self.synchronised {
+                ""
+            } else {
+                ", not synchronised"
+            }
+        )
+    }
+}
+
+// Parses the output of `chronyc -c tracking`, a single CSV line with the fields: reference ID,
+// reference name, stratum, reference time, system time correction, last offset, RMS offset,
+// frequency, residual frequency, skew, root delay, root dispersion, update interval and leap
+// status
+pub fn parse_chrony_tracking(line: &str) -> Result<ClockStatus, &'static str> {
+    let fields: Vec<&str> = line.trim().split(',').collect();
+    if fields.len() < 14 {
+        return Err("Unexpected chronyc output");
+    }
+
+    let number = |i: usize| -> Result<f64, &'static str> {
+        fields[i]
+            .trim()
+            .parse()
+            .map_err(|_| "Unexpected chronyc output")
+    };
+
+    // The correction is what has to be added to the system clock, so it's positive if slow
+    let correction_s = number(4)?;
+    let root_delay_s = number(10)?;
+    let root_dispersion_s = number(11)?;
+
+    Ok(ClockStatus {
+        reference: String::from(fields[1]),
+        stratum: number(2)? as u32,
+        offset_s: -correction_s,
+        max_error_s: correction_s.abs() + root_dispersion_s + root_delay_s / 2.0,
+        synchronised: fields[13].trim() != "Not synchronised",
+    })
+}
+
+// Queries the clock status from chronyd by running `chronyc -c tracking`, so it fails if
+// chronyc is not installed or chronyd is not running
+pub fn query_clock_status() -> Result<ClockStatus, &'static str> {
+    let output = Command::new("chronyc")
+        .args(["-c", "tracking"])
+        .output()
+        .map_err(|_| "Cannot run chronyc")?;
+    if !output.status.success() {
+        return Err("chronyc cannot reach chronyd");
+    }
+
+    parse_chrony_tracking(&String::from_utf8_lossy(&output.stdout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_chrony_tracking() {
+        let status = parse_chrony_tracking(
+            "C0A80001,192.168.0.1,3,1700000000.123456789,0.000200000,-0.000010000,0.000050000,\
+             -12.345,0.001,0.020,0.004000000,0.000500000,64.2,Normal\n",
+        )
+        .unwrap();
+
+        assert_eq!(status.stratum, 3);
+        assert!(status.synchronised);
+        assert!((status.offset_s + 200e-6).abs() < 1e-12);
+        assert!((status.max_error_s - 2.7e-3).abs() < 1e-12);
+    }
+}
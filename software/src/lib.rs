@@ -0,0 +1,8 @@
+//! Host side of the transmitter: planning of transmissions, re-exported from `planner`, their
+//! analysis, and a client to upload them to the transmitter over any transport.
+pub mod ambiguity;
+pub mod clock;
+pub mod port;
+pub mod transmitter;
+
+pub use planner::{bandplan, orders, patterns, planfile, report, sequence, upload};
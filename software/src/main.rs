@@ -1,18 +1,22 @@
-use chrono::{self, DateTime, SubsecRound, TimeZone, Utc};
-use common::comm_messages::UplinkMsg::{
-    ClearBuffer, Ping, PushFracn, PushPLLChange, StartNow, StopNow, UploadDone,
-};
-use common::comm_messages::{MAX_UPLINK_MSG_SIZE, UplinkMsg};
-use common::sequence::Sequence;
-use serialport::{DataBits, FlowControl, Parity, SerialPort, SerialPortType, StopBits};
+use chrono::{self, Utc};
+use common::comm_messages::DEFAULT_BAUD_RATE;
+use serialport::SerialPort;
+use software::ambiguity::{Ambiguity, AmbiguitySettings};
+use software::bandplan::BandPlan;
+use software::clock;
+use software::orders::FrequencyOrder;
+use software::planfile::PlanFile;
+use software::port::{self, PortSelection};
+use software::report::QuantizationReport;
+use software::transmitter::{Transmitter, plan_duration_us};
+use software::upload::UploadModel;
+use software::{bandplan, orders, sequence};
 use std::fmt::Write;
 use std::fs;
-use std::io::{ErrorKind, Read};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
 
-mod orders;
-mod sequence;
-
 // Pseudorandom sequence (PRSeq) generation:
 // A file is used to read the "order frequencies" (used to fine-tune the system),
 // which specifies a series of intervals in time and frequency where a
@@ -20,164 +24,665 @@ mod sequence;
 // The pseudorandomness of the sequence is further made time-dependent by the time seed.
 // Each sequenced is seeded by the hash of the UTC time of the biggest multiple of
 // TIME_SEED_ROUND_S seconds that's before the start of the sequence. This rounding
-// reduces dependency on very precise clock.
-
-fn find_port() -> Result<String, &'static str> {
-    let ports = serialport::available_ports().unwrap();
-    for port in ports {
-        if let SerialPortType::UsbPort(info) = port.port_type {
-            if let Some(m) = info.manufacturer {
-                if m == "STMicroelectronics" {
-                    println!("Chosen port {}", port.port_name);
-                    return Ok(port.port_name);
-                }
-            }
-        }
+// reduces dependency on very precise clock. The index of each order and an optional shared
+// secret (--secret) are also hashed in, see sequence::derive_seed.
+
+// Set on Ctrl-C, so that the transmission is stopped instead of left playing
+static ABORT: AtomicBool = AtomicBool::new(false);
+// Set while a transmitter is open, otherwise there's nothing to stop and Ctrl-C exits at once
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+fn frequencies_to_str(freqs: &Vec<(f64, f64, f64)>, harmonic: usize) -> String {
+    // Recorded so the receiver doesn't move the frequencies to the harmonic again
+    let mut out = format!("# harmonic, {}\n", harmonic);
+
+    for freq in freqs {
+        writeln!(&mut out, "{:.6},{:.6},{:.6}", freq.0, freq.1, freq.2).unwrap();
     }
 
-    Err("ST-Link VCOM port not found")
+    out
+}
+
+// Reads the (frame bytes, send to ack us) measured on previous runs
+fn read_calibration(path: &str) -> Vec<(usize, f64)> {
+    let Ok(file) = fs::read_to_string(path) else {
+        return Vec::new();
+    };
+
+    file.lines()
+        .filter_map(|line| {
+            let (bytes, us) = line.split_once(',')?;
+            Some((bytes.trim().parse().ok()?, us.trim().parse().ok()?))
+        })
+        .collect()
 }
 
-fn frequencies_to_str(freqs: &Vec<(f64, f64)>) -> String {
+fn write_calibration(path: &str, timings: &[(usize, f64)]) {
+    // Only the most recent measurements are kept
+    const MAX_CALIBRATION_ENTRIES: usize = 10_000;
+
     let mut out = String::new();
+    let skip = timings.len().saturating_sub(MAX_CALIBRATION_ENTRIES);
+    for (bytes, us) in &timings[skip..] {
+        writeln!(&mut out, "{},{:.0}", bytes, us).unwrap();
+    }
+    fs::write(path, out).unwrap();
+}
 
-    for freq in freqs {
-        writeln!(&mut out, "{:.6},{:.6}", freq.0, freq.1).unwrap();
+// Drops the frequencies from the stop moment on. Lines without a time, such as the harmonic
+// header, are kept as they are.
+fn truncate_freqs(file: &str, stop_s: f64) -> String {
+    let mut out = String::new();
+    for line in file.lines() {
+        let t: Option<f64> = line.split(',').next().and_then(|t| t.trim().parse().ok());
+        match t {
+            Some(t) if t >= stop_s => break,
+            _ => writeln!(&mut out, "{}", line).unwrap(),
+        }
     }
 
     out
 }
 
-fn send_seq(port: &mut Box<dyn SerialPort>, seq: &Sequence) -> Result<(), &'static str> {
-    for slice in seq.fracn_buffer.chunks(32) {
-        let mut fixedslice: [u16; 32] = [0; 32];
-        // The rest of elements may be left zeroed, as we pass the len separately
-        fixedslice[..slice.len()].copy_from_slice(slice);
-        let cmd = PushFracn(slice.len() as u8, fixedslice);
-        send(port, &cmd).unwrap();
+// Removes the frequencies after the stop moment from the frequencies file, so that it only
+// contains what was actually transmitted
+fn truncate_freqs_file(path: &str, stop_s: f64) {
+    let Ok(file) = fs::read_to_string(path) else {
+        eprintln!("Cannot read frequencies file {} to truncate it", path);
+        return;
+    };
+
+    fs::write(path, truncate_freqs(&file, stop_s)).unwrap();
+    println!("Truncated frequencies file {} to epoch {:.3}", path, stop_s);
+}
+
+// Runs the plan, and if it fails, is aborted or panics, stops the transmitter so that it
+// doesn't keep playing the queued sequences
+fn run_plan_or_stop(
+    transmitter: &mut Transmitter<Box<dyn SerialPort>>,
+    plan: &sequence::UploadPlan,
+    start_epoch: i64,
+    freqs_path: &str,
+) -> Result<(), &'static str> {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| transmitter.run_plan(plan, start_epoch)))
+        .unwrap_or(Err("Panicked while running the plan"));
+
+    if result.is_err() {
+        let stop_s = Utc::now().timestamp_micros() as f64 * 1e-6;
+        match transmitter.stop() {
+            Ok(()) => {
+                println!("Transmitter stopped");
+                truncate_freqs_file(freqs_path, stop_s);
+            }
+            Err(e) => eprintln!(
+                "Failed to stop the transmitter, {}. It may keep transmitting queued sequences",
+                e
+            ),
+        }
     }
 
-    for pll in &seq.pllchange_buffer {
-        send(port, &PushPLLChange(*pll)).unwrap();
+    result
+}
+
+// Inserts the epoch before the extension of the path, so each cycle of the daemon mode writes
+// its own files (freqs.csv -> freqs_1700000000.csv)
+fn epoch_path(path: &str, epoch: i64) -> String {
+    match path.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() && !ext.contains('/') => {
+            format!("{}_{}.{}", stem, epoch, ext)
+        }
+        _ => format!("{}_{}", path, epoch),
     }
+}
 
-    Ok(())
+// Options that apply to every transmission
+struct Settings {
+    out_path: String,
+    save_plan_path: Option<String>,
+    quant_report_path: Option<String>,
+    secret: Option<String>,
+    fref_hz: f64,
+    harmonic: usize,
+    bandplan: Option<(String, BandPlan)>,
+    // Where to write the ambiguity surface of the sequence, if analysed
+    ambiguity: Option<(String, AmbiguitySettings)>,
+    upload_model: UploadModel,
+    port: PortSelection,
+    // Largest tolerated error of the host clock
+    max_clock_error_s: f64,
+    // Refuse to transmit if the host clock is not known to be within max_clock_error_s,
+    // instead of just warning. Opt-in, as not every host runs chrony.
+    enforce_clock: bool,
+    // Name output files after the start epoch
+    per_epoch_files: bool,
 }
 
-fn uplink_to_str(msg: &UplinkMsg) -> &str {
-    match msg {
-        Ping() => return "Ping",
-        PushPLLChange(_) => return "PLLChange",
-        PushFracn(_, _) => return "PushFracn",
-        UploadDone() => return "UploadDone",
-        ClearBuffer() => return "ClearBuffer",
-        StartNow() => return "StartNow",
-        StopNow() => return "StopNow",
+impl Settings {
+    fn output_path(&self, path: &str, start_epoch: i64) -> String {
+        if self.per_epoch_files {
+            epoch_path(path, start_epoch)
+        } else {
+            String::from(path)
+        }
     }
 }
 
-// Tries to send data, waiting for acknowledge and retrying
-fn send(port: &mut Box<dyn SerialPort>, msg: &UplinkMsg) -> Result<(), &'static str> {
-    let mut databuf: [u8; MAX_UPLINK_MSG_SIZE] = [0; MAX_UPLINK_MSG_SIZE];
-    let try_encoded = postcard::to_slice_cobs(msg, &mut databuf);
-    let data = if let Ok(data) = try_encoded {
-        data
-    } else {
-        return Err("Error decoding");
+// Checks that the host clock is synchronised, as the sequences are seeded and timed by it.
+// Returns its status, if known.
+fn check_clock(settings: &Settings) -> Result<Option<clock::ClockStatus>, String> {
+    let (status, problem) = match clock::query_clock_status() {
+        Ok(status) => {
+            println!("Host clock {}", status);
+            let problem = if !status.synchronised {
+                Some(String::from("Host clock is not synchronised"))
+            } else if status.max_error_s > settings.max_clock_error_s {
+                Some(format!(
+                    "Host clock error may be up to {:.3}ms, over the {:.3}ms limit",
+                    status.max_error_s * 1e3,
+                    settings.max_clock_error_s * 1e3
+                ))
+            } else {
+                None
+            };
+            (Some(status), problem)
+        }
+        Err(e) => (None, Some(format!("Unknown host clock status, {}", e))),
     };
 
-    const RETRIES: usize = 4;
+    match problem {
+        Some(problem) if settings.enforce_clock => {
+            Err(format!("{}, refusing to transmit", problem))
+        }
+        Some(problem) => {
+            eprintln!("Warning: {}", problem);
+            Ok(status)
+        }
+        None => Ok(status),
+    }
+}
+
+// A plan ready to be transmitted, and everything about it that gets written to files
+struct PreparedPlan {
+    plan: sequence::UploadPlan,
+    start_epoch: i64,
+    clock_status: Option<clock::ClockStatus>,
+    quant_report: QuantizationReport,
+    // (time, freq, amplitude) of the harmonic the receiver is tuned to
+    harmonic_freqs: Vec<(f64, f64, f64)>,
+    ambiguity: Option<Ambiguity>,
+}
 
-    port.clear(serialport::ClearBuffer::Input).unwrap();
+// Builds and checks the plan for a transmission starting at start_epoch, without writing any
+// file yet (see write_plan_files). Fails if the plan can't be transmitted.
+fn prepare_plan(
+    settings: &Settings,
+    orders: &[FrequencyOrder],
+    saved_plan: Option<&PlanFile>,
+    start_epoch: i64,
+) -> Result<PreparedPlan, String> {
+    let secret = settings.secret.as_ref().map(|s| s.as_bytes());
 
-    let mut numtry = 0;
+    let clock_status = check_clock(settings)?;
 
-    while numtry < RETRIES {
-        let send_moment = Utc::now();
-        port.write_all(data).unwrap();
-        port.flush().unwrap();
-        /*println!(
-            "Sent {} try {}, waiting for reply...",
-            uplink_to_str(msg),
-            numtry + 1
-        );*/
+    let quant_report = QuantizationReport::new(orders, start_epoch, secret)?;
+    print!("{}", quant_report.summary());
 
-        let mut read_buffer: [u8; 1] = [0];
-        let try_read = port.read(&mut read_buffer);
-        if let Err(e) = try_read {
-            if e.kind() == ErrorKind::TimedOut {
-                println!("Timed out");
-                break;
-            } else {
-                return Err("I/O error");
-            }
+    // Note that this seeding is good enough as rand does some "entropy increasing" on the seed
+    let plan = match saved_plan {
+        Some(saved) => saved
+            .upload_plan()
+            .map_err(|e| format!("Error in plan file, {}", e))?,
+        None => sequence::build_upload_plan(orders, start_epoch, secret, &settings.upload_model)
+            .map_err(|e| format!("Cannot build plan, {}", e))?,
+    };
+    println!("Built upload plan with {} uploads", plan.len(),);
+
+    let freqs = sequence::build_frequencies_fref(plan.values(), start_epoch, settings.fref_hz);
+    let harmonic_freqs = sequence::harmonic_frequencies(&freqs, settings.harmonic);
+
+    // Analyse the sequence as the receiver correlator will see it
+    let ambiguity = match &settings.ambiguity {
+        Some((_, ambiguity_settings)) => {
+            let hops: Vec<(f64, f64)> = harmonic_freqs.iter().map(|&(t, f, _)| (t, f)).collect();
+            let ambiguity = Ambiguity::new(&hops, ambiguity_settings)?;
+            print!("{}", ambiguity.summary());
+            Some(ambiguity)
         }
+        None => None,
+    };
 
-        if read_buffer[0] == 0 {
-            println!("NoAck received, trying again!");
-            // no ack, try again...
-        } else {
-            //println!("Ok!");
-            let ok_moment = Utc::now();
-            let delta = ok_moment.signed_duration_since(send_moment);
-            println!(
-                "From send to ack took {}us",
-                delta.num_microseconds().unwrap()
-            );
-            return Ok(());
+    // Refuse to transmit anything (hops or their harmonics) outside the band plan
+    if let Some((path, bandplan)) = &settings.bandplan {
+        const MAX_SHOWN_VIOLATIONS: usize = 20;
+        let violations = bandplan.check(&freqs);
+        if !violations.is_empty() {
+            for violation in violations.iter().take(MAX_SHOWN_VIOLATIONS) {
+                eprintln!("Band plan violation {}", violation);
+            }
+            return Err(format!(
+                "Sequence violates band plan {} in {} places, refusing to transmit",
+                path,
+                violations.len()
+            ));
         }
-        numtry += 1;
+        println!("Sequence complies with band plan {}", path);
     }
 
-    Err("Too many tries without reply")
+    Ok(PreparedPlan {
+        plan,
+        start_epoch,
+        clock_status,
+        quant_report,
+        harmonic_freqs,
+        ambiguity,
+    })
 }
 
-fn sleep_until_precise(start_date: DateTime<Utc>, until_off_us: i64) {
+// Writes the frequencies (and other requested) files of a prepared plan, once it's certain
+// to be transmitted
+fn write_plan_files(settings: &Settings, orders: &[FrequencyOrder], prepared: &PreparedPlan) {
+    let start_epoch = prepared.start_epoch;
+    let output_path = |path: &str| settings.output_path(path, start_epoch);
+    let secret = settings.secret.as_ref().map(|s| s.as_bytes());
+
+    if let Some(path) = &settings.quant_report_path {
+        let path = output_path(path);
+        fs::write(&path, prepared.quant_report.to_csv()).unwrap();
+        println!("Written quantization report to file {}", path);
+    }
+
+    if let Some(path) = &settings.save_plan_path {
+        let path = output_path(path);
+        let mut file = PlanFile::new(
+            env!("CARGO_PKG_VERSION"),
+            orders,
+            start_epoch,
+            secret,
+            settings.fref_hz,
+            &prepared.plan,
+        );
+        file.clock_offset_s = prepared.clock_status.as_ref().map(|status| status.offset_s);
+        fs::write(&path, file.to_json()).unwrap();
+        println!("Written plan to file {}", path);
+    }
+
+    let out_path = output_path(&settings.out_path);
+    fs::write(
+        &out_path,
+        frequencies_to_str(&prepared.harmonic_freqs, settings.harmonic),
+    )
+    .unwrap();
+    println!(
+        "Written frequencies of harmonic {} (amplitude {:.1}dB relative to fundamental) to file {}",
+        settings.harmonic,
+        20.0 * sequence::harmonic_amplitude(settings.harmonic).log10(),
+        out_path
+    );
+
+    if let (Some((path, _)), Some(ambiguity)) = (&settings.ambiguity, &prepared.ambiguity) {
+        let path = output_path(path);
+        ndarray_npy::write_npy(&path, &ambiguity.surface).unwrap();
+        println!("Written ambiguity surface to file {}", path);
+    }
+}
+
+// Microseconds from now until the first upload of the plan must start, negative if late
+fn time_to_first_upload_us(plan: &sequence::UploadPlan, start_epoch: i64) -> i64 {
+    let first_upload_off_us = *plan.keys().next().unwrap();
+    start_epoch * 1_000_000 + first_upload_off_us - Utc::now().timestamp_micros()
+}
+
+// Sleeps until the given UNIX time, exiting if aborted meanwhile
+fn sleep_until(epoch_s: i64) {
+    const POLL_MS: i64 = 200;
+
     loop {
-        let now_exact = Utc::now();
-        let offset_us = now_exact
-            .signed_duration_since(start_date)
-            .num_microseconds()
-            .unwrap();
-
-        const BUSY_LOOP_MARGIN_US: i64 = 50_000;
-        let remain = until_off_us - offset_us;
-
-        if remain <= 0 {
-            // Ready to start
-            break;
-        } else if remain > BUSY_LOOP_MARGIN_US {
-            println!("Sleeping for {}us", remain - BUSY_LOOP_MARGIN_US);
-            std::thread::sleep(Duration::from_micros((remain - BUSY_LOOP_MARGIN_US) as u64));
-        } else {
-            // Busy loop
+        if ABORT.load(Ordering::Relaxed) {
+            std::process::exit(1);
+        }
+        let remain_ms = epoch_s * 1000 - Utc::now().timestamp_millis();
+        if remain_ms <= 0 {
+            return;
+        }
+        std::thread::sleep(Duration::from_millis(remain_ms.min(POLL_MS) as u64));
+    }
+}
+
+// Repeats the transmission every period_s seconds, starting at offset_s seconds past each
+// multiple of period_s since the UNIX epoch (so at fixed UTC times). The sequence is
+// regenerated each cycle, so it's seeded by that cycle's start epoch.
+fn run_daemon(
+    settings: &Settings,
+    orders: &[FrequencyOrder],
+    saved_plan: Option<&PlanFile>,
+    period_s: i64,
+    offset_s: i64,
+    calibration_path: Option<&str>,
+    mut timings: Vec<(usize, f64)>,
+) -> ! {
+    // Time to wait before trying to open the port again
+    const RECONNECT_S: u64 = 5;
+
+    let mut transmitter: Option<Transmitter<Box<dyn SerialPort>>> = None;
+    // The previous transmission must end before the next one is uploaded
+    let mut busy_until_s = Utc::now().timestamp();
+
+    loop {
+        let earliest_s = busy_until_s.max(Utc::now().timestamp());
+        let mut start_epoch = (earliest_s - offset_s).div_euclid(period_s) * period_s + offset_s;
+
+        // Find the next cycle whose first upload is still ahead
+        let prepared = loop {
+            if ABORT.load(Ordering::Relaxed) {
+                std::process::exit(1);
+            }
+            if start_epoch < earliest_s {
+                start_epoch += period_s;
+                continue;
+            }
+
+            println!("Next cycle starts at epoch {}", start_epoch);
+            let prepared = match prepare_plan(settings, orders, saved_plan, start_epoch) {
+                Ok(prepared) => prepared,
+                Err(e) => {
+                    eprintln!("Skipping cycle at epoch {}, {}", start_epoch, e);
+                    // Don't retry right away, as it would most likely fail again
+                    sleep_until(start_epoch);
+                    start_epoch += period_s;
+                    continue;
+                }
+            };
+
+            let plan = &prepared.plan;
+            let first_upload_us = start_epoch * 1_000_000 + plan.keys().next().unwrap();
+            if first_upload_us < busy_until_s * 1_000_000
+                || time_to_first_upload_us(plan, start_epoch) < 0
+            {
+                println!("Not enough time to upload cycle at epoch {}", start_epoch);
+                start_epoch += period_s;
+                continue;
+            }
+
+            break prepared;
+        };
+        let plan = &prepared.plan;
+
+        // (Re)connect to the transmitter, which may have been unplugged since the last cycle
+        while transmitter.is_none() && time_to_first_upload_us(plan, start_epoch) > 0 {
+            if ABORT.load(Ordering::Relaxed) {
+                std::process::exit(1);
+            }
+            match port::open_port(&settings.port) {
+                Ok(opened) => {
+                    let mut opened = Transmitter::new(opened).with_abort_flag(&ABORT);
+                    // Measurements carry over reconnections
+                    opened.timings = std::mem::take(&mut timings);
+                    transmitter = Some(opened);
+                    CONNECTED.store(true, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    eprintln!("{}, retrying in {}s", e, RECONNECT_S);
+                    std::thread::sleep(Duration::from_secs(RECONNECT_S));
+                }
+            }
+        }
+        let Some(opened) = transmitter.as_mut() else {
+            eprintln!("No transmitter for cycle at epoch {}", start_epoch);
+            busy_until_s = start_epoch + 1;
+            continue;
+        };
+
+        // Only the files of cycles that are actually attempted are written
+        write_plan_files(settings, orders, &prepared);
+        let freqs_path = settings.output_path(&settings.out_path, start_epoch);
+        let result = run_plan_or_stop(opened, plan, start_epoch, &freqs_path);
+
+        if let Some(path) = calibration_path {
+            write_calibration(path, &opened.timings);
+        }
+
+        match result {
+            Ok(()) => println!("Cycle at epoch {} transmitted", start_epoch),
+            Err(e) => {
+                eprintln!("Cycle at epoch {} failed, {}", start_epoch, e);
+                if ABORT.load(Ordering::Relaxed) {
+                    std::process::exit(1);
+                }
+                // Reopen the port, in case it was unplugged
+                timings = std::mem::take(&mut opened.timings);
+                transmitter = None;
+                CONNECTED.store(false, Ordering::Relaxed);
+            }
         }
+        busy_until_s = start_epoch + plan_duration_us(plan).div_ceil(1_000_000) as i64;
     }
 }
 
 fn main() {
     let mut pargs = pico_args::Arguments::from_env();
 
+    // The first Ctrl-C stops the transmission gracefully (or exits if there is no transmitter
+    // yet), a second one kills the process
+    ctrlc::set_handler(|| {
+        if ABORT.swap(true, Ordering::Relaxed) || !CONNECTED.load(Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+        eprintln!("Interrupted, stopping the transmission");
+    })
+    .expect("Error setting Ctrl-C handler");
+
+    // Only list the serial ports, to find the transmitter
+    if pargs.contains("--list-ports") {
+        if let Err(e) = port::list_ports() {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     // Only generate the time-freq CSV, don't do anything with the serial ports
     let dry = pargs.contains(["-", "--dry"]);
     if dry {
         println!("Running in dry mode");
     }
 
-    let orders_path: String = pargs
-        .opt_free_from_str()
-        .unwrap()
-        .unwrap_or(String::from("orders.csv"));
-
+    // Upload the sequences of a previously saved plan (see --save-plan) instead of generating
+    // them from the orders file. The saved orders are used for reports.
+    let saved_plan_path: Option<String> = pargs.opt_value_from_str("--plan").unwrap();
     let out_path: String = pargs
         .opt_value_from_str("--out")
         .unwrap()
         .unwrap_or(String::from("freqs.csv"));
 
-    let orders = orders::parse_orders(fs::read_to_string(orders_path).unwrap()).unwrap();
-    println!("Read {} orders", orders.len());
+    // Daemon mode: transmit every this many seconds, at this many seconds past each period
+    let every_s: Option<i64> = pargs.opt_value_from_str("--every").unwrap();
+    let offset_s: i64 = pargs.opt_value_from_str("--offset").unwrap().unwrap_or(0);
+
+    let saved_plan = saved_plan_path.map(|path| {
+        match PlanFile::from_json(&fs::read_to_string(&path).unwrap()) {
+            Ok(saved) => {
+                println!(
+                    "Read plan file {} generated by version {} for epoch {}",
+                    path, saved.software_version, saved.start_epoch
+                );
+                saved
+            }
+            Err(e) => {
+                eprintln!("Error in plan file {}, {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    });
 
     let date_str: Option<String> = pargs.opt_value_from_str("--date").unwrap();
+
+    // Shared secret mixed into the time seed, needed to regenerate the sequence on reception
+    let secret: Option<String> = pargs.opt_value_from_str("--secret").unwrap();
+
+    // Optionally write requested versus achieved frequency of each hop to this CSV
+    let quant_report_path: Option<String> = pargs.opt_value_from_str("--quant-report").unwrap();
+
+    // Ack latencies measured on previous runs are stored in this file, to calibrate the upload
+    // time of sequences, and this run's ones added to it
+    let calibration_path: Option<String> = pargs.opt_value_from_str("--calibration").unwrap();
+    let timings = calibration_path
+        .as_deref()
+        .map(read_calibration)
+        .unwrap_or_default();
+
+    // Port of the transmitter, found by USB VID:PID (any STMicroelectronics device by default)
+    // and serial number unless given
+    let port = PortSelection {
+        name: pargs.opt_value_from_str("--port").unwrap(),
+        vid_pid: pargs
+            .opt_value_from_fn("--vid-pid", port::parse_vid_pid)
+            .unwrap(),
+        serial: pargs.opt_value_from_str("--serial").unwrap(),
+        // Must match the baud rate the firmware was built with
+        baud: pargs
+            .opt_value_from_str("--baud")
+            .unwrap()
+            .unwrap_or(DEFAULT_BAUD_RATE),
+    };
+
+    let mut upload_model = UploadModel {
+        baud: port.baud,
+        ..Default::default()
+    };
+    upload_model.calibrate(&timings);
+    println!(
+        "Upload model at {} baud with {:.0}us ack latency",
+        upload_model.baud, upload_model.ack_latency_us
+    );
+
+    // Measured frequency of the PLL reference, used to predict the emitted frequencies
+    let fref_hz: f64 = pargs
+        .opt_value_from_str("--fref")
+        .unwrap()
+        .or(saved_plan.as_ref().map(|saved| saved.fref_hz))
+        .unwrap_or(sequence::FREF_HZ);
+
+    let save_plan_path: Option<String> = pargs.opt_value_from_str("--save-plan").unwrap();
+
+    // Harmonic of the square wave output written to the frequencies file, for when it's
+    // received on a harmonic instead of the fundamental
+    let harmonic: usize = pargs.opt_value_from_str("--harmonic").unwrap().unwrap_or(1);
+    if harmonic == 0 || harmonic.is_multiple_of(2) {
+        eprintln!("Harmonic must be odd, square waves have no even harmonics");
+        std::process::exit(1);
+    }
+
+    let bandplan_path: Option<String> = pargs.opt_value_from_str("--bandplan").unwrap();
+    let bandplan = bandplan_path.map(|path| {
+        match bandplan::parse_bandplan(fs::read_to_string(&path).unwrap()) {
+            Ok(bandplan) => (path, bandplan),
+            Err(e) => {
+                eprintln!("Error in band plan file {}, {}", path, e);
+                std::process::exit(1);
+            }
+        }
+    });
+
+    // Write the delay-Doppler ambiguity of the sequence to this npy file, and report on it.
+    // The receiver sample rate changes the time and frequency resolution of its spectrogram.
+    let ambiguity_path: Option<String> = pargs.opt_value_from_str("--ambiguity").unwrap();
+    let ambiguity_rate: Option<f64> = pargs.opt_value_from_str("--ambiguity-rate").unwrap();
+    let ambiguity = ambiguity_path.map(|path| {
+        let mut ambiguity_settings = AmbiguitySettings::default();
+        if let Some(rate) = ambiguity_rate {
+            ambiguity_settings.samp_rate = rate;
+        }
+        (path, ambiguity_settings)
+    });
+
+    // The host clock should be synchronised (by chrony) to within this many ms. Only a warning
+    // is given otherwise, unless transmitting is refused with --require-synced-clock.
+    let max_clock_error_ms: f64 = pargs
+        .opt_value_from_str("--max-clock-error")
+        .unwrap()
+        .unwrap_or(10.0);
+    let require_synced_clock = pargs.contains("--require-synced-clock");
+
+    // Explain the divider configuration chosen for each order
+    let explain_dividers = pargs.contains("--dividers");
+
+    // The orders file is a free argument, so it's parsed after all options
+    let orders = if let Some(saved) = &saved_plan {
+        saved.orders.clone()
+    } else {
+        let orders_path: String = pargs
+            .opt_free_from_str()
+            .unwrap()
+            .unwrap_or(String::from("orders.csv"));
+
+        match orders::parse_orders(fs::read_to_string(&orders_path).unwrap()) {
+            Ok(orders) => orders,
+            Err(e) => {
+                eprintln!("Error in orders file {}, {}", orders_path, e);
+                std::process::exit(1);
+            }
+        }
+    };
+    println!("Read {} orders", orders.len());
+
+    if explain_dividers {
+        for (i, order) in orders.iter().enumerate() {
+            let (flow, fhigh) = order.band();
+            match sequence::split_band(flow, fhigh) {
+                Ok(choices) => {
+                    for choice in choices {
+                        println!(
+                            "Order {}: divn = {} divp = {} vcosel = {}, {}",
+                            i, choice.divn, choice.divp, choice.vcosel, choice.reason
+                        );
+                    }
+                }
+                Err(e) => println!("Order {}: {}", i, e),
+            }
+        }
+    }
+
+    let settings = Settings {
+        out_path,
+        save_plan_path,
+        quant_report_path,
+        secret,
+        fref_hz,
+        harmonic,
+        bandplan,
+        ambiguity,
+        upload_model,
+        port,
+        max_clock_error_s: max_clock_error_ms * 1e-3,
+        enforce_clock: !dry && require_synced_clock,
+        per_epoch_files: every_s.is_some(),
+    };
+
+    if let Some(period_s) = every_s {
+        if period_s <= 0 {
+            eprintln!("Daemon period must be positive");
+            std::process::exit(1);
+        }
+        if dry {
+            eprintln!("Daemon mode can't run dry");
+            std::process::exit(1);
+        }
+
+        println!(
+            "Running as daemon every {}s at {}s past each period",
+            period_s, offset_s
+        );
+        run_daemon(
+            &settings,
+            &orders,
+            saved_plan.as_ref(),
+            period_s,
+            offset_s,
+            calibration_path.as_deref(),
+            timings,
+        );
+    }
+
     let date = match date_str {
         None => chrono::Utc::now(),
         Some(str) => chrono::DateTime::parse_from_rfc2822(str.as_str())
@@ -191,44 +696,68 @@ fn main() {
         start_epoch - chrono::Utc::now().timestamp()
     );
 
-    // Note that this seeding is good enough as rand does some "entropy increasing" on the seed
-    let plan = sequence::build_upload_plan(orders, start_epoch);
-    println!("Built upload plan with {} uploads", plan.len(),);
-
-    let freqs = sequence::build_frequencies(&plan, start_epoch);
-    fs::write(&out_path, frequencies_to_str(&freqs)).unwrap();
-    println!("Written frequencies to file {}", out_path);
+    let prepared = match prepare_plan(&settings, &orders, saved_plan.as_ref(), start_epoch) {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    write_plan_files(&settings, &orders, &prepared);
+    let plan = prepared.plan;
 
     if !dry {
-        let port_name = find_port().unwrap();
-        let mut port = serialport::new(port_name, 115_200)
-            .timeout(Duration::from_secs_f64(1.0))
-            .flow_control(FlowControl::None)
-            .parity(Parity::None)
-            .stop_bits(StopBits::One)
-            .data_bits(DataBits::Eight)
-            .open()
-            .expect("Failed to open STM32 port");
-
-        let start_date = Utc.timestamp_opt(start_epoch, 0).unwrap();
-        let mut ctr = 0;
-
-        for (&upload_off_us, seq) in &plan {
-            println!("Waiting to upload sequence number {}", ctr);
-            sleep_until_precise(start_date, upload_off_us);
-
-            println!("Sending sequence {}", ctr);
-            send(&mut port, &ClearBuffer()).unwrap();
-            send_seq(&mut port, seq).unwrap();
-            send(&mut port, &UploadDone()).unwrap();
-            if ctr == 0 {
-                println!("Waiting to start first sequence");
-                sleep_until_precise(start_date, 0);
-                send(&mut port, &StartNow()).unwrap();
+        let late_us = -time_to_first_upload_us(&plan, start_epoch);
+        if late_us > 0 {
+            eprintln!(
+                "Start epoch is too close, the first upload should have started {:.3}s ago",
+                late_us as f64 * 1e-6
+            );
+            std::process::exit(1);
+        }
+
+        let port = match port::open_port(&settings.port) {
+            Ok(port) => port,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
             }
-            ctr += 1;
+        };
+        let mut transmitter = Transmitter::new(port).with_abort_flag(&ABORT);
+        CONNECTED.store(true, Ordering::Relaxed);
+        transmitter.timings = timings;
+        let result = run_plan_or_stop(&mut transmitter, &plan, start_epoch, &settings.out_path);
+
+        if let Some(path) = calibration_path {
+            write_calibration(&path, &transmitter.timings);
+            println!("Written upload calibration to file {}", path);
         }
 
-        println!("Sequence finished");
+        match result {
+            Ok(()) => println!("Sequence finished"),
+            Err(e) => {
+                eprintln!("Sequence not finished, {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncation_keeps_harmonic_header() {
+        let freqs = vec![(10.0, 7e6, 1.0), (11.0, 7.1e6, 1.0), (12.0, 7.2e6, 1.0)];
+        let file = frequencies_to_str(&freqs, 3);
+
+        let truncated = truncate_freqs(&file, 11.5);
+        let lines: Vec<&str> = truncated.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "# harmonic, 3");
+        assert!(lines[2].starts_with("11.000000,"));
+
+        assert_eq!(truncate_freqs(&file, 5.0), "# harmonic, 3\n");
     }
 }
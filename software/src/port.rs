@@ -0,0 +1,110 @@
+// Finding and opening the serial port of the transmitter
+
+use serialport::{
+    DataBits, FlowControl, Parity, SerialPort, SerialPortInfo, SerialPortType, StopBits,
+};
+use std::time::Duration;
+
+// USB vendor ID of STMicroelectronics, used by the ST-Link VCOM port
+const ST_VID: u16 = 0x0483;
+
+// Which serial port the transmitter is on, and how to talk to it
+pub struct PortSelection {
+    // Port name, used as is instead of searching for the transmitter
+    pub name: Option<String>,
+    // USB vendor and product ID of the transmitter
+    pub vid_pid: Option<(u16, u16)>,
+    // USB serial number of the transmitter, to tell apart several of them
+    pub serial: Option<String>,
+    pub baud: u32,
+}
+
+// Parses a USB VID:PID pair given in hexadecimal, such as 0483:374e
+pub fn parse_vid_pid(s: &str) -> Result<(u16, u16), &'static str> {
+    let (vid, pid) = s.split_once(':').ok_or("Expected VID:PID")?;
+    let parse = |id: &str| u16::from_str_radix(id.trim(), 16).map_err(|_| "Invalid VID:PID");
+    Ok((parse(vid)?, parse(pid)?))
+}
+
+pub fn list_ports() -> Result<(), &'static str> {
+    let ports = serialport::available_ports().map_err(|_| "Cannot list serial ports")?;
+    if ports.is_empty() {
+        println!("No serial ports found");
+    }
+
+    for port in ports {
+        match port.port_type {
+            SerialPortType::UsbPort(info) => println!(
+                "{}: USB {:04x}:{:04x} serial {} manufacturer {} product {}",
+                port.port_name,
+                info.vid,
+                info.pid,
+                info.serial_number.as_deref().unwrap_or("-"),
+                info.manufacturer.as_deref().unwrap_or("-"),
+                info.product.as_deref().unwrap_or("-"),
+            ),
+            SerialPortType::PciPort => println!("{}: PCI", port.port_name),
+            SerialPortType::BluetoothPort => println!("{}: Bluetooth", port.port_name),
+            SerialPortType::Unknown => println!("{}: unknown", port.port_name),
+        }
+    }
+
+    Ok(())
+}
+
+// Whether the port is a transmitter matching the selection. Without a VID:PID, any
+// STMicroelectronics device is accepted.
+fn port_matches(port: &SerialPortInfo, selection: &PortSelection) -> bool {
+    let SerialPortType::UsbPort(info) = &port.port_type else {
+        return false;
+    };
+
+    let id_matches = match selection.vid_pid {
+        Some((vid, pid)) => info.vid == vid && info.pid == pid,
+        None => info.vid == ST_VID || info.manufacturer.as_deref() == Some("STMicroelectronics"),
+    };
+    let serial_matches = match &selection.serial {
+        Some(serial) => info.serial_number.as_ref() == Some(serial),
+        None => true,
+    };
+
+    id_matches && serial_matches
+}
+
+pub fn find_port(selection: &PortSelection) -> Result<String, String> {
+    if let Some(name) = &selection.name {
+        return Ok(name.clone());
+    }
+
+    let ports = serialport::available_ports().map_err(|_| "Cannot list serial ports")?;
+    let mut matching: Vec<String> = ports
+        .into_iter()
+        .filter(|port| port_matches(port, selection))
+        .map(|port| port.port_name)
+        .collect();
+
+    match matching.len() {
+        0 => Err(String::from("Transmitter port not found, see --list-ports")),
+        1 => {
+            let port_name = matching.remove(0);
+            println!("Chosen port {}", port_name);
+            Ok(port_name)
+        }
+        _ => Err(format!(
+            "Several transmitter ports found ({}), choose one with --port or --serial",
+            matching.join(", ")
+        )),
+    }
+}
+
+pub fn open_port(selection: &PortSelection) -> Result<Box<dyn SerialPort>, String> {
+    let port_name = find_port(selection)?;
+    serialport::new(&port_name, selection.baud)
+        .timeout(Duration::from_secs_f64(1.0))
+        .flow_control(FlowControl::None)
+        .parity(Parity::None)
+        .stop_bits(StopBits::One)
+        .data_bits(DataBits::Eight)
+        .open()
+        .map_err(|e| format!("Failed to open port {}, {}", port_name, e))
+}
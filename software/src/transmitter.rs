@@ -0,0 +1,313 @@
+// Client of the transmitter, uploading sequences and controlling their playback. Messages are
+// sent as COBS frames, each answered by a single byte: non-zero to acknowledge it, zero if it
+// must be sent again.
+
+use chrono::{DateTime, TimeZone, Utc};
+use common::comm_messages::UplinkMsg::{
+    ClearBuffer, Ping, PushFracn, PushPLLChange, StartNow, StopNow, UploadDone,
+};
+use common::comm_messages::{MAX_UPLINK_MSG_SIZE, UplinkMsg};
+use common::sequence::Sequence;
+use planner::sequence::{PLLCHANGE_US, UploadPlan};
+use serialport::SerialPort;
+use std::io::{ErrorKind, Read};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// Times a frame is sent without being acknowledged before giving up
+const RETRIES: usize = 4;
+
+// Link to the transmitter
+pub trait Transport {
+    // Discards anything received and not read yet
+    fn clear_input(&mut self) -> Result<(), &'static str>;
+    // Sends a whole frame
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), &'static str>;
+    // Waits for the reply to a frame, true if acknowledged, or None if none arrives in time
+    fn read_reply(&mut self) -> Result<Option<bool>, &'static str>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn clear_input(&mut self) -> Result<(), &'static str> {
+        self.clear(serialport::ClearBuffer::Input)
+            .map_err(|_| "I/O error")
+    }
+
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        self.write_all(data).map_err(|_| "I/O error")?;
+        self.flush().map_err(|_| "I/O error")
+    }
+
+    fn read_reply(&mut self) -> Result<Option<bool>, &'static str> {
+        let mut read_buffer: [u8; 1] = [0];
+        match self.read(&mut read_buffer) {
+            Ok(_) => Ok(Some(read_buffer[0] != 0)),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(None),
+            Err(_) => Err("I/O error"),
+        }
+    }
+}
+
+// Transport keeping every frame sent in memory, to use the client without a transmitter.
+// Acknowledges all frames, except for the first `nacks` ones.
+#[derive(Default)]
+pub struct MemoryTransport {
+    pub frames: Vec<Vec<u8>>,
+    pub nacks: usize,
+}
+
+impl MemoryTransport {
+    // Decodes the frames sent, including the repeated ones
+    pub fn messages(&self) -> Vec<UplinkMsg> {
+        self.frames
+            .iter()
+            .map(|frame| {
+                let mut frame = frame.clone();
+                postcard::from_bytes_cobs(&mut frame).expect("Frames are uplink messages")
+            })
+            .collect()
+    }
+}
+
+impl Transport for MemoryTransport {
+    fn clear_input(&mut self) -> Result<(), &'static str> {
+        Ok(())
+    }
+
+    fn write_frame(&mut self, data: &[u8]) -> Result<(), &'static str> {
+        self.frames.push(data.to_vec());
+        Ok(())
+    }
+
+    fn read_reply(&mut self) -> Result<Option<bool>, &'static str> {
+        if self.nacks > 0 {
+            self.nacks -= 1;
+            Ok(Some(false))
+        } else {
+            Ok(Some(true))
+        }
+    }
+}
+
+// Duration of the transmission of the plan, in us
+pub fn plan_duration_us(plan: &UploadPlan) -> u64 {
+    let mut out = 0;
+    for change in plan.values().flat_map(|seq| seq.pllchange_buffer.iter()) {
+        out += PLLCHANGE_US as u64 + change.for_ticks as u64 * change.tim_us as u64;
+    }
+
+    out
+}
+
+pub struct Transmitter<T: Transport> {
+    transport: T,
+    // (frame bytes, send to ack us) of every acknowledged frame, to calibrate the upload model
+    pub timings: Vec<(usize, f64)>,
+    // Once set, uploads and waits fail instead of going on
+    abort: Option<&'static AtomicBool>,
+}
+
+impl<T: Transport> Transmitter<T> {
+    pub fn new(transport: T) -> Self {
+        Transmitter {
+            transport,
+            timings: Vec::new(),
+            abort: None,
+        }
+    }
+
+    // Makes uploads and waits fail once `abort` is set, such as from a signal handler
+    pub fn with_abort_flag(mut self, abort: &'static AtomicBool) -> Self {
+        self.abort = Some(abort);
+        self
+    }
+
+    pub fn transport(&self) -> &T {
+        &self.transport
+    }
+
+    fn check_abort(&self) -> Result<(), &'static str> {
+        match self.abort {
+            Some(abort) if abort.load(Ordering::Relaxed) => Err("Aborted"),
+            _ => Ok(()),
+        }
+    }
+
+    // Tries to send a message, waiting for acknowledge and retrying
+    pub fn send(&mut self, msg: &UplinkMsg) -> Result<(), &'static str> {
+        let mut databuf: [u8; MAX_UPLINK_MSG_SIZE] = [0; MAX_UPLINK_MSG_SIZE];
+        let try_encoded = postcard::to_slice_cobs(msg, &mut databuf);
+        let data = if let Ok(data) = try_encoded {
+            data
+        } else {
+            return Err("Error decoding");
+        };
+
+        self.transport.clear_input()?;
+
+        let mut numtry = 0;
+
+        while numtry < RETRIES {
+            let send_moment = Utc::now();
+            self.transport.write_frame(data)?;
+
+            match self.transport.read_reply()? {
+                None => {
+                    println!("Timed out");
+                    break;
+                }
+                Some(false) => {
+                    println!("NoAck received, trying again!");
+                    // no ack, try again...
+                }
+                Some(true) => {
+                    //println!("Ok!");
+                    let ok_moment = Utc::now();
+                    let delta = ok_moment.signed_duration_since(send_moment);
+                    let delta_us = delta.num_microseconds().unwrap();
+                    println!("From send to ack took {}us", delta_us);
+                    self.timings.push((data.len(), delta_us as f64));
+                    return Ok(());
+                }
+            }
+            numtry += 1;
+        }
+
+        Err("Too many tries without reply")
+    }
+
+    // Replaces the sequence waiting to be played with this one
+    pub fn upload(&mut self, seq: &Sequence) -> Result<(), &'static str> {
+        self.send(&ClearBuffer())?;
+
+        for slice in seq.fracn_buffer.chunks(32) {
+            let mut fixedslice: [u16; 32] = [0; 32];
+            // The rest of elements may be left zeroed, as we pass the len separately
+            fixedslice[..slice.len()].copy_from_slice(slice);
+            let cmd = PushFracn(slice.len() as u8, fixedslice);
+            self.check_abort()?;
+            self.send(&cmd)?;
+        }
+
+        for pll in &seq.pllchange_buffer {
+            self.check_abort()?;
+            self.send(&PushPLLChange(*pll))?;
+        }
+
+        self.send(&UploadDone())
+    }
+
+    pub fn ping(&mut self) -> Result<(), &'static str> {
+        self.send(&Ping())
+    }
+
+    pub fn start(&mut self) -> Result<(), &'static str> {
+        self.send(&StartNow())
+    }
+
+    // Stops the transmitter and discards whatever it has queued. Not affected by the abort
+    // flag, so it can be used to clean up after an abort.
+    pub fn stop(&mut self) -> Result<(), &'static str> {
+        self.send(&StopNow())?;
+        self.send(&ClearBuffer())
+    }
+
+    // Fails if aborted while sleeping
+    fn sleep_until_precise(
+        &self,
+        start_date: DateTime<Utc>,
+        until_off_us: i64,
+    ) -> Result<(), &'static str> {
+        const BUSY_LOOP_MARGIN_US: i64 = 50_000;
+        // Sleeps are split so that an abort is noticed quickly
+        const MAX_SLEEP_US: i64 = 100_000;
+
+        let mut announced = false;
+
+        loop {
+            self.check_abort()?;
+
+            let now_exact = Utc::now();
+            let offset_us = now_exact
+                .signed_duration_since(start_date)
+                .num_microseconds()
+                .unwrap();
+
+            let remain = until_off_us - offset_us;
+
+            if remain <= 0 {
+                // Ready to start
+                return Ok(());
+            } else if remain > BUSY_LOOP_MARGIN_US {
+                if !announced {
+                    println!("Sleeping for {}us", remain - BUSY_LOOP_MARGIN_US);
+                    announced = true;
+                }
+                let sleep_us = (remain - BUSY_LOOP_MARGIN_US).min(MAX_SLEEP_US);
+                std::thread::sleep(Duration::from_micros(sleep_us as u64));
+            } else {
+                // Busy loop
+            }
+        }
+    }
+
+    // Uploads each sequence of the plan when scheduled, starting the first one at start_epoch,
+    // and waits until the whole plan has been transmitted
+    pub fn run_plan(&mut self, plan: &UploadPlan, start_epoch: i64) -> Result<(), &'static str> {
+        let start_date = Utc.timestamp_opt(start_epoch, 0).unwrap();
+
+        for (ctr, (&upload_off_us, seq)) in plan.iter().enumerate() {
+            println!("Waiting to upload sequence number {}", ctr);
+            self.sleep_until_precise(start_date, upload_off_us)?;
+
+            println!("Sending sequence {}", ctr);
+            self.upload(seq)?;
+            if ctr == 0 {
+                println!("Waiting to start first sequence");
+                self.sleep_until_precise(start_date, 0)?;
+                self.start()?;
+            }
+        }
+
+        println!("Waiting for the transmission to end");
+        self.sleep_until_precise(start_date, plan_duration_us(plan) as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use common::sequence::PLLChange;
+
+    #[test]
+    fn upload_retries_unacknowledged_frames() {
+        let mut seq = Sequence::default();
+        seq.fracn_buffer.extend_from_slice(&[100; 40]).unwrap();
+        seq.pllchange_buffer
+            .extend_from_slice(&[PLLChange {
+                divn: 50,
+                vcosel: false,
+                divp: 8,
+                start_tick: 0,
+                for_ticks: 40,
+                tim_us: 1000,
+            }])
+            .unwrap();
+
+        let mut transmitter = Transmitter::new(MemoryTransport {
+            nacks: 1,
+            ..Default::default()
+        });
+        transmitter.upload(&seq).unwrap();
+
+        let messages = transmitter.transport().messages();
+        assert_eq!(messages.len(), 6);
+        assert!(matches!(messages[0], ClearBuffer()));
+        assert!(matches!(messages[1], ClearBuffer()));
+        assert!(matches!(messages[2], PushFracn(32, _)));
+        assert!(matches!(messages[3], PushFracn(8, _)));
+        assert!(matches!(messages[4], PushPLLChange(_)));
+        assert!(matches!(messages[5], UploadDone()));
+        assert_eq!(transmitter.timings.len(), 5);
+    }
+}